@@ -0,0 +1,191 @@
+//! Filtering peers by address
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A rejected UDP sender keeps sending, so rejections are only logged this often
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Network in CIDR notation (a plain address is a network of its own)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, b)| (a, Some(b)));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|err| format!("invalid address {addr:?}: {err}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|x| *x <= max)
+                .ok_or_else(|| format!("invalid prefix length {prefix:?}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // dual stack sockets see IPv4 peers as IPv4-mapped IPv6 addresses
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Which peers we talk to
+#[derive(Debug)]
+pub struct Acl {
+    /// Peers have to be in one of these networks, unless it's empty
+    allow: Vec<Cidr>,
+    /// Peers in these networks are always rejected
+    deny: Vec<Cidr>,
+    /// When a rejection was last logged, and how many went unlogged since
+    logged: Mutex<(Option<Instant>, u32)>,
+}
+
+impl Acl {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self {
+            allow,
+            deny,
+            logged: Mutex::new((None, 0)),
+        }
+    }
+    /// Whether to accept the peer at `addr`, logging it if not
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        let ip = addr.ip();
+        if !self.deny.iter().any(|x| x.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip)))
+        {
+            return true;
+        }
+        let mut logged = self.logged.lock().unwrap();
+        let (last, unlogged) = &mut *logged;
+        if last.is_some_and(|x| x.elapsed() < LOG_INTERVAL) {
+            *unlogged += 1;
+        } else {
+            match *unlogged {
+                0 => log::info!("rejected {addr}"),
+                n => log::info!("rejected {addr} (and {n} more since the last one logged)"),
+            }
+            *last = Some(Instant::now());
+            *unlogged = 0;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> SocketAddr {
+        SocketAddr::new(ip(s), 4000)
+    }
+
+    #[test]
+    fn parses_networks_and_addresses() {
+        assert_eq!(
+            cidr("192.168.1.0/24"),
+            Cidr {
+                addr: ip("192.168.1.0"),
+                prefix: 24
+            }
+        );
+        assert_eq!(cidr("10.0.0.1").prefix, 32);
+        assert_eq!(cidr("fe80::1").prefix, 128);
+        assert_eq!(cidr("::/0").prefix, 0);
+        for bad in [
+            "",
+            "garbage",
+            "192.168.1.0/",
+            "192.168.1.0/33",
+            "fe80::/129",
+            "192.168.1.0/-1",
+            "192.168.1.0/24/8",
+            "192.168.1/24",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn matches_by_prefix() {
+        let net = cidr("192.168.1.0/24");
+        assert!(net.contains(ip("192.168.1.0")));
+        assert!(net.contains(ip("192.168.1.255")));
+        assert!(!net.contains(ip("192.168.2.1")));
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.7")));
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+        assert!(cidr("fe80::1/128").contains(ip("fe80::1")));
+        assert!(!cidr("fe80::1/128").contains(ip("fe80::2")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_peers_against_ipv4_networks() {
+        assert!(cidr("192.168.1.0/24").contains(ip("::ffff:192.168.1.5")));
+        assert!(!cidr("192.168.1.0/24").contains(ip("::ffff:192.168.2.5")));
+        // but the families don't mix otherwise
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(ip("192.168.1.5")));
+    }
+
+    #[test]
+    fn allows_everyone_by_default() {
+        let acl = Acl::new(Vec::new(), Vec::new());
+        assert!(acl.allows(peer("203.0.113.7")));
+        assert!(acl.allows(peer("2001:db8::1")));
+    }
+
+    #[test]
+    fn denying_beats_allowing() {
+        let acl = Acl::new(vec![cidr("192.168.1.0/24")], vec![cidr("192.168.1.13")]);
+        assert!(acl.allows(peer("192.168.1.5")));
+        assert!(acl.allows(peer("::ffff:192.168.1.5")));
+        assert!(!acl.allows(peer("192.168.1.13")));
+        assert!(!acl.allows(peer("::ffff:192.168.1.13")));
+        assert!(!acl.allows(peer("10.0.0.1")));
+        // without an allow list, only the denied peers are rejected
+        let acl = Acl::new(Vec::new(), vec![cidr("10.0.0.0/8")]);
+        assert!(!acl.allows(peer("10.1.2.3")));
+        assert!(acl.allows(peer("192.168.1.5")));
+    }
+}
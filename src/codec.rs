@@ -0,0 +1,247 @@
+//! Audio compression for the network stream
+//!
+//! The ring buffers always contain PCM in the stream format, audio only gets compressed right
+//! before being sent, and decompressed right after being received.
+
+use clap::{Args, ValueEnum};
+
+use crate::format::StreamFormat;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    /// Uncompressed audio in the stream format
+    Pcm,
+    #[cfg(feature = "opus")]
+    Opus,
+}
+
+impl Codec {
+    pub fn to_raw(self) -> u8 {
+        match self {
+            Self::Pcm => 0,
+            #[cfg(feature = "opus")]
+            Self::Opus => 1,
+        }
+    }
+    pub fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            0 => Self::Pcm,
+            #[cfg(feature = "opus")]
+            1 => Self::Opus,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Args, Copy, Clone, Debug)]
+pub struct CodecOpts {
+    /// Codec to send the audio with
+    #[arg(long, value_enum, default_value_t = Codec::Pcm)]
+    pub codec: Codec,
+
+    /// Opus bitrate in bits per second
+    #[cfg(feature = "opus")]
+    #[arg(long, default_value_t = 128000)]
+    pub bitrate: i32,
+
+    /// Opus frame duration in ms (2.5, 5, 10, 20, 40 or 60)
+    #[cfg(feature = "opus")]
+    #[arg(long, default_value_t = 10.0)]
+    pub frame_ms: f32,
+}
+
+impl Default for CodecOpts {
+    fn default() -> Self {
+        Codec::Pcm.into()
+    }
+}
+
+/// Default settings for a codec
+impl From<Codec> for CodecOpts {
+    fn from(codec: Codec) -> Self {
+        Self {
+            codec,
+            #[cfg(feature = "opus")]
+            bitrate: 128000,
+            #[cfg(feature = "opus")]
+            frame_ms: 10.0,
+        }
+    }
+}
+
+/// Opus packets can't be bigger than this
+#[cfg(feature = "opus")]
+pub const MAX_OPUS_PACKET: usize = 1275;
+/// Longest possible opus frame
+#[cfg(feature = "opus")]
+const MAX_OPUS_FRAME_MS: usize = 120;
+
+#[cfg(feature = "opus")]
+fn opus_channels(format: &StreamFormat) -> Result<opus::Channels, String> {
+    match format.channels {
+        1 => Ok(opus::Channels::Mono),
+        2 => Ok(opus::Channels::Stereo),
+        n => Err(format!("opus doesn't support {n} channels")),
+    }
+}
+
+#[cfg(feature = "opus")]
+pub struct OpusEncoder {
+    enc: opus::Encoder,
+    format: StreamFormat,
+    frames: usize,
+    pcm: Vec<f32>,
+}
+
+#[cfg(feature = "opus")]
+impl OpusEncoder {
+    pub fn new(format: StreamFormat, opts: &CodecOpts) -> Result<Self, String> {
+        if ![2.5, 5.0, 10.0, 20.0, 40.0, 60.0].contains(&opts.frame_ms) {
+            return Err(format!(
+                "{}ms is not a valid opus frame size",
+                opts.frame_ms
+            ));
+        }
+        let mut enc = opus::Encoder::new(
+            format.rate,
+            opus_channels(&format)?,
+            opus::Application::LowDelay,
+        )
+        .map_err(|err| format!("opus doesn't support {format}: {err}"))?;
+        enc.set_bitrate(opus::Bitrate::Bits(opts.bitrate))
+            .map_err(|err| format!("invalid opus bitrate: {err}"))?;
+        // in-band FEC lets the receiver recover a lost packet from the next one
+        enc.set_inband_fec(true)
+            .and_then(|()| enc.set_packet_loss_perc(10))
+            .map_err(|err| format!("opus fec: {err}"))?;
+        let frames = (format.rate as f32 * opts.frame_ms / 1000.0) as usize;
+        Ok(Self {
+            enc,
+            format,
+            frames,
+            pcm: vec![0.0; frames * usize::from(format.channels)],
+        })
+    }
+    /// Amount of frames encoded into a single packet
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+    /// Encode exactly `frames()` frames of PCM, returning the packet length
+    pub fn encode(&mut self, pcm: &[u8], out: &mut [u8]) -> Result<usize, opus::Error> {
+        let fmt = self.format.sample_format;
+        for (x, sample) in self.pcm.iter_mut().zip(pcm.chunks_exact(fmt.bytes())) {
+            *x = fmt.decode(sample);
+        }
+        self.enc.encode_float(&self.pcm, out)
+    }
+}
+
+#[cfg(feature = "opus")]
+pub struct OpusDecoder {
+    dec: opus::Decoder,
+    format: StreamFormat,
+    /// Size of the last decoded packet
+    frames: usize,
+    pcm: Vec<f32>,
+    out: Vec<u8>,
+}
+
+#[cfg(feature = "opus")]
+impl OpusDecoder {
+    pub fn new(format: StreamFormat) -> Result<Self, String> {
+        let dec = opus::Decoder::new(format.rate, opus_channels(&format)?)
+            .map_err(|err| format!("opus doesn't support {format}: {err}"))?;
+        let max_frames = format.rate as usize * MAX_OPUS_FRAME_MS / 1000;
+        Ok(Self {
+            dec,
+            format,
+            frames: format.rate as usize / 100,
+            pcm: vec![0.0; max_frames * usize::from(format.channels)],
+            out: vec![0; max_frames * format.frame_bytes()],
+        })
+    }
+    /// Amount of frames in a packet
+    pub fn packet_frames(&self, packet: &[u8]) -> Result<usize, opus::Error> {
+        opus::packet::get_nb_samples(packet, self.format.rate)
+    }
+    /// Decode a packet into PCM
+    pub fn decode(&mut self, packet: &[u8]) -> Result<&mut [u8], opus::Error> {
+        self.frames = self.packet_frames(packet)?;
+        self.run(packet, self.frames, false)
+    }
+    /// Amount of frames in the last decoded packet
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+    pub fn frame_bytes(&self) -> usize {
+        self.format.frame_bytes()
+    }
+    /// Conceal up to a packet's worth of lost frames using opus's PLC
+    pub fn conceal(&mut self, frames: usize) -> Result<&mut [u8], opus::Error> {
+        self.run(&[], frames.min(self.frames), false)
+    }
+    /// Recover up to a packet's worth of lost frames right before the given packet, using its
+    /// FEC data if present
+    pub fn recover(&mut self, next: &[u8], frames: usize) -> Result<&mut [u8], opus::Error> {
+        self.run(next, frames.min(self.frames), true)
+    }
+    fn run(&mut self, packet: &[u8], frames: usize, fec: bool) -> Result<&mut [u8], opus::Error> {
+        let channels = usize::from(self.format.channels);
+        let frames = self
+            .dec
+            .decode_float(packet, &mut self.pcm[..frames * channels], fec)?;
+        let fmt = self.format.sample_format;
+        let len = frames * self.format.frame_bytes();
+        for (x, sample) in self.pcm[..frames * channels]
+            .iter()
+            .zip(self.out[..len].chunks_exact_mut(fmt.bytes()))
+        {
+            fmt.encode(*x, sample);
+        }
+        Ok(&mut self.out[..len])
+    }
+}
+
+pub enum Encoder {
+    /// PCM is sent as is
+    Pcm,
+    #[cfg(feature = "opus")]
+    Opus(OpusEncoder),
+}
+
+impl Encoder {
+    pub fn new(format: StreamFormat, opts: &CodecOpts) -> Result<Self, String> {
+        #[cfg(not(feature = "opus"))]
+        let _ = format;
+        Ok(match opts.codec {
+            Codec::Pcm => Self::Pcm,
+            #[cfg(feature = "opus")]
+            Codec::Opus => Self::Opus(OpusEncoder::new(format, opts)?),
+        })
+    }
+}
+
+pub enum Decoder {
+    Pcm,
+    #[cfg(feature = "opus")]
+    Opus(OpusDecoder),
+}
+
+impl Decoder {
+    pub fn new(format: StreamFormat, codec: Codec) -> Result<Self, String> {
+        #[cfg(not(feature = "opus"))]
+        let _ = format;
+        Ok(match codec {
+            Codec::Pcm => Self::Pcm,
+            #[cfg(feature = "opus")]
+            Codec::Opus => Self::Opus(OpusDecoder::new(format)?),
+        })
+    }
+    pub fn codec(&self) -> Codec {
+        match self {
+            Self::Pcm => Codec::Pcm,
+            #[cfg(feature = "opus")]
+            Self::Opus(_) => Codec::Opus,
+        }
+    }
+}
@@ -0,0 +1,304 @@
+//! Packet loss concealment
+//!
+//! Instead of abruptly switching to silence when audio goes missing (which clicks), the last
+//! played audio is extrapolated and faded out, and once real audio comes back, it's crossfaded
+//! with the extrapolated signal.
+
+use clap::ValueEnum;
+
+use crate::format::StreamFormat;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Concealment {
+    /// Play silence
+    Silence,
+    /// Hold the last frame and fade it out
+    Fade,
+    /// Repeat the last pitch period of the waveform and fade it out
+    Repeat,
+}
+
+/// Amount of played audio to keep around for extrapolation
+const HISTORY_MS: usize = 30;
+/// Pitch period search range
+const MIN_PERIOD_MS: f32 = 2.5;
+const MAX_PERIOD_MS: usize = 15;
+/// Crossfade length when going back from concealed to real audio
+const XFADE_MS: usize = 3;
+
+pub struct Concealer {
+    mode: Concealment,
+    format: StreamFormat,
+    channels: usize,
+    /// Recently played samples, interleaved, oldest first
+    history: Vec<f32>,
+    history_len: usize,
+    min_period: usize,
+    max_period: usize,
+    /// Length of the extrapolated signal's fade out
+    fade_len: usize,
+    xfade_len: usize,
+    /// Amount of frames concealed so far, if currently concealing
+    lost: Option<usize>,
+    /// Pitch period (in frames) of the concealed audio, 0 if unknown
+    period: usize,
+}
+
+impl Concealer {
+    pub fn new(mode: Concealment, format: StreamFormat) -> Self {
+        let rate = format.rate as usize;
+        let channels = usize::from(format.channels);
+        let history_len = rate * HISTORY_MS / 1000;
+        Self {
+            mode,
+            format,
+            channels,
+            history: Vec::with_capacity(history_len * channels * 2),
+            history_len,
+            min_period: (rate as f32 * MIN_PERIOD_MS / 1000.0) as usize,
+            max_period: rate * MAX_PERIOD_MS / 1000,
+            fade_len: rate
+                * match mode {
+                    Concealment::Silence => 0,
+                    Concealment::Fade => 10,
+                    Concealment::Repeat => 40,
+                }
+                / 1000,
+            xfade_len: rate * XFADE_MS / 1000,
+            lost: None,
+            period: 0,
+        }
+    }
+    /// Fill the buffer with concealed audio
+    pub fn conceal(&mut self, data: &mut [u8]) {
+        // all-zero bytes are silence in every supported format
+        data.fill(0);
+        if self.mode == Concealment::Silence || self.history.is_empty() {
+            return;
+        }
+        let lost = match self.lost {
+            Some(lost) => lost,
+            None => {
+                self.period = self.find_period();
+                0
+            }
+        };
+        let frame_bytes = self.format.frame_bytes();
+        let sample_bytes = self.format.sample_format.bytes();
+        let frames = data.len() / frame_bytes;
+        for (i, frame) in data.chunks_exact_mut(frame_bytes).enumerate() {
+            let n = lost + i;
+            if n >= self.fade_len {
+                break;
+            }
+            for (ch, sample) in frame.chunks_exact_mut(sample_bytes).enumerate() {
+                let x = self.extrapolate(n, ch);
+                self.format.sample_format.encode(x, sample);
+            }
+        }
+        self.lost = Some(lost + frames);
+    }
+    /// Must be called with all real audio before it's played
+    ///
+    /// The audio is remembered for future concealment, and if it follows concealed audio, it
+    /// gets crossfaded with it.
+    pub fn played(&mut self, data: &mut [u8]) {
+        if self.mode == Concealment::Silence || data.is_empty() {
+            return;
+        }
+        let frame_bytes = self.format.frame_bytes();
+        let sample_bytes = self.format.sample_format.bytes();
+        if let Some(lost) = self.lost.take() {
+            for (i, frame) in data
+                .chunks_exact_mut(frame_bytes)
+                .take(self.xfade_len)
+                .enumerate()
+            {
+                let t = (i + 1) as f32 / (self.xfade_len + 1) as f32;
+                for (ch, sample) in frame.chunks_exact_mut(sample_bytes).enumerate() {
+                    let x = self.format.sample_format.decode(sample) * t
+                        + self.extrapolate(lost + i, ch) * (1.0 - t);
+                    self.format.sample_format.encode(x, sample);
+                }
+            }
+        }
+        let frames = data.len() / frame_bytes;
+        let keep = frames.min(self.history_len);
+        for sample in
+            data[(frames - keep) * frame_bytes..frames * frame_bytes].chunks_exact(sample_bytes)
+        {
+            self.history.push(self.format.sample_format.decode(sample));
+        }
+        let excess = self
+            .history
+            .len()
+            .saturating_sub(self.history_len * self.channels);
+        self.history.drain(..excess);
+    }
+    /// Extrapolated value of channel `ch` of the `n`th frame since the loss started
+    fn extrapolate(&self, n: usize, ch: usize) -> f32 {
+        if n >= self.fade_len {
+            return 0.0;
+        }
+        let frames = self.history.len() / self.channels;
+        let frame = if self.period == 0 {
+            frames - 1
+        } else {
+            frames - self.period + n % self.period
+        };
+        let gain = 1.0 - n as f32 / self.fade_len as f32;
+        self.history[frame * self.channels + ch] * gain
+    }
+    /// Find the pitch period of the recent audio via autocorrelation
+    fn find_period(&self) -> usize {
+        if self.mode != Concealment::Repeat {
+            return 0;
+        }
+        let frames = self.history.len() / self.channels;
+        let window = self.max_period;
+        if frames < self.max_period + window {
+            return 0;
+        }
+        let mono = |i: usize| -> f32 {
+            self.history[i * self.channels..(i + 1) * self.channels]
+                .iter()
+                .sum()
+        };
+        let (mut best, mut best_corr) = (0, 0.0);
+        for lag in self.min_period..=self.max_period {
+            let (mut xy, mut xx, mut yy) = (0.0, 0.0, 0.0);
+            for i in frames - window..frames {
+                let (x, y) = (mono(i), mono(i - lag));
+                xy += x * y;
+                xx += x * x;
+                yy += y * y;
+            }
+            let corr = xy / (xx * yy).sqrt().max(f32::EPSILON);
+            if corr > best_corr {
+                (best, best_corr) = (lag, corr);
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::F32LE,
+    };
+    /// Period of the test tone in frames (300 Hz), which has multiples in the search range too
+    const PERIOD: usize = 160;
+
+    /// Frames `from..to` of a tone, exactly periodic so every multiple of the period correlates
+    /// equally well
+    fn tone(from: usize, to: usize) -> Vec<f32> {
+        (from..to)
+            .map(|i| (2.0 * std::f32::consts::PI * (i % PERIOD) as f32 / PERIOD as f32).sin())
+            .collect()
+    }
+
+    fn encode(frames: &[f32]) -> Vec<u8> {
+        frames
+            .iter()
+            .flat_map(|x| x.to_le_bytes().repeat(2))
+            .collect()
+    }
+
+    fn decode(data: &[u8]) -> Vec<f32> {
+        data.chunks_exact(FORMAT.frame_bytes())
+            .map(|x| {
+                assert_eq!(x[..4], x[4..]);
+                f32::from_le_bytes(x[..4].try_into().unwrap())
+            })
+            .collect()
+    }
+
+    /// A concealer which has played 50ms of the tone
+    fn concealer(mode: Concealment) -> Concealer {
+        let mut concealer = Concealer::new(mode, FORMAT);
+        for i in 0..5 {
+            concealer.played(&mut encode(&tone(i * 480, (i + 1) * 480)));
+        }
+        concealer
+    }
+
+    /// Conceal `frames` frames, in 10ms chunks
+    fn conceal(concealer: &mut Concealer, frames: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        while out.len() < frames {
+            let mut data = vec![0xff; 480.min(frames - out.len()) * FORMAT.frame_bytes()];
+            concealer.conceal(&mut data);
+            out.extend(decode(&data));
+        }
+        out
+    }
+
+    #[test]
+    fn conceals_with_silence() {
+        let mut concealer = concealer(Concealment::Silence);
+        assert!(conceal(&mut concealer, 1000).iter().all(|x| *x == 0.0));
+        // and real audio is played as is
+        let mut data = encode(&tone(0, 480));
+        concealer.played(&mut data);
+        assert_eq!(decode(&data), tone(0, 480));
+    }
+
+    #[test]
+    fn fades_out_to_silence() {
+        let mut concealer = concealer(Concealment::Fade);
+        let fade_len = concealer.fade_len;
+        let last = tone(2399, 2400)[0];
+        let out = conceal(&mut concealer, fade_len + 1000);
+        assert_eq!(out[0], last);
+        for x in out.windows(2) {
+            assert!(x[1].abs() <= x[0].abs());
+        }
+        assert!(out[fade_len - 1].abs() < 0.01);
+        assert!(out[fade_len..].iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn repeats_a_tones_period() {
+        let mut concealer = concealer(Concealment::Repeat);
+        assert_eq!(concealer.find_period(), PERIOD);
+        let fade_len = concealer.fade_len;
+        let out = conceal(&mut concealer, fade_len + 1000);
+        // the tone goes on, fading out
+        for (n, (x, y)) in out.iter().zip(tone(2400, 2400 + fade_len)).enumerate() {
+            let gain = 1.0 - n as f32 / fade_len as f32;
+            assert!(
+                (x - y * gain).abs() < 1e-6,
+                "frame {n}: {x} instead of {}",
+                y * gain
+            );
+        }
+        assert!(out[fade_len..].iter().all(|x| *x == 0.0));
+    }
+
+    #[test]
+    fn crossfades_back_to_real_audio() {
+        let mut concealer = concealer(Concealment::Repeat);
+        let xfade_len = concealer.xfade_len;
+        let mut out = conceal(&mut concealer, 250);
+        // real audio comes back at a level the concealed tone is nowhere near
+        let mut data = encode(&[-0.5; 480]);
+        concealer.played(&mut data);
+        out.extend(decode(&data));
+        let max_step = 2.0 * std::f32::consts::PI / PERIOD as f32 + 1.5 / xfade_len as f32;
+        for (i, x) in out.windows(2).enumerate() {
+            assert!(
+                (x[1] - x[0]).abs() < max_step,
+                "jumped from {} to {} at frame {i}",
+                x[0],
+                x[1]
+            );
+        }
+        assert!(out[250 + xfade_len..].iter().all(|x| *x == -0.5));
+    }
+}
@@ -0,0 +1,83 @@
+//! cpal device and stream format negotiation, shared by playback and capture
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+use crate::format::{SampleFormat, StreamFormat};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
+
+pub fn cpal_format(fmt: SampleFormat) -> cpal::SampleFormat {
+    match fmt {
+        SampleFormat::S16LE => cpal::SampleFormat::I16,
+        // cpal doesn't support packed 24-bit samples, so they get widened to 32 bits
+        SampleFormat::S24LE | SampleFormat::S32LE => cpal::SampleFormat::I32,
+        SampleFormat::F32LE => cpal::SampleFormat::F32,
+    }
+}
+
+/// Convert packed s24le samples to s32le
+pub fn widen_s24(src: &[u8], dst: &mut [u8]) {
+    for (src, dst) in src.chunks_exact(3).zip(dst.chunks_exact_mut(4)) {
+        dst[0] = 0;
+        dst[1..].copy_from_slice(src);
+    }
+}
+
+/// Convert s32le samples to packed s24le
+pub fn narrow_s24(src: &[u8], dst: &mut [u8]) {
+    for (src, dst) in src.chunks_exact(4).zip(dst.chunks_exact_mut(3)) {
+        dst.copy_from_slice(&src[1..]);
+    }
+}
+
+/// Find the device called `name`, or the default one
+pub fn find(
+    name: Option<String>,
+    direction: Direction,
+) -> Result<cpal::Device, Box<dyn std::error::Error>> {
+    let host = cpal::default_host();
+    let Some(name) = name else {
+        let device = match direction {
+            Direction::Input => host.default_input_device(),
+            Direction::Output => host.default_output_device(),
+        };
+        return Ok(device.ok_or("no default device")?);
+    };
+    let mut devices = match direction {
+        Direction::Input => host.input_devices()?,
+        Direction::Output => host.output_devices()?,
+    };
+    let device = devices.find(|dev| {
+        let dev = dev.name();
+        log::info!("trying device {dev:?}");
+        matches!(dev, Ok(dev) if dev == name)
+    });
+    Ok(device.ok_or("device not found")?)
+}
+
+/// Find a config of `device` that can play or record `format`
+pub fn config(
+    device: &cpal::Device,
+    format: StreamFormat,
+    direction: Direction,
+) -> Result<cpal::StreamConfig, Box<dyn std::error::Error>> {
+    let sample_format = cpal_format(format.sample_format);
+    let mut supported_configs_range: Box<dyn Iterator<Item = _>> = match direction {
+        Direction::Input => Box::new(device.supported_input_configs()?),
+        Direction::Output => Box::new(device.supported_output_configs()?),
+    };
+    let supported_config = supported_configs_range
+        .find(|cfg: &cpal::SupportedStreamConfigRange| {
+            cfg.min_sample_rate().0 <= format.rate
+                && cfg.max_sample_rate().0 >= format.rate
+                && cfg.channels() == format.channels
+                && cfg.sample_format() == sample_format
+        })
+        .ok_or_else(|| format!("device doesn't support {format}"))?
+        .with_sample_rate(cpal::SampleRate(format.rate));
+    Ok(supported_config.into())
+}
@@ -0,0 +1,198 @@
+//! Sending the recorded audio to multiple clients at once
+//!
+//! TCP clients each get their own queue, so a slow client only makes its own audio skip instead
+//! of stalling everyone else, and gets dropped if it can't keep up for too long. UDP clients
+//! subscribe by periodically sending a subscribe packet to the recorder, and all get the same
+//! packets (the kernel's socket buffer acts as the queue there, so there's nothing to stall).
+
+use std::{
+    net::{Shutdown, SocketAddr, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf_blocking::BlockingRb;
+
+use crate::{acl::Acl, format::StreamFormat, packet, psk, RingCons, RingProd};
+
+/// How much audio a TCP client may lag behind before it starts skipping
+const CLIENT_QUEUE_MS: usize = 250;
+/// Drop TCP clients that have been stalled for this long
+const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+/// Forget UDP subscribers that haven't renewed their subscription in this long
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Client {
+    addr: SocketAddr,
+    prod: RingProd,
+    /// Used to shut the connection down when dropping the client
+    conn: TcpStream,
+    stalled_since: Option<Instant>,
+}
+
+pub struct Fanout {
+    format: StreamFormat,
+    clients: Mutex<Vec<Client>>,
+}
+
+impl Fanout {
+    pub fn new(format: StreamFormat) -> Self {
+        Self {
+            format,
+            clients: Mutex::new(Vec::new()),
+        }
+    }
+    /// Add a client, returning its queue
+    pub fn subscribe(&self, addr: SocketAddr, conn: &TcpStream) -> Option<RingCons> {
+        let conn = match conn.try_clone() {
+            Ok(x) => x,
+            Err(err) => {
+                log::error!("tcp clone: {err}");
+                return None;
+            }
+        };
+        let len = self.format.rate as usize * CLIENT_QUEUE_MS / 1000 * self.format.frame_bytes();
+        let (prod, cons) = BlockingRb::new(len).split();
+        self.clients.lock().unwrap().push(Client {
+            addr,
+            prod,
+            conn,
+            stalled_since: None,
+        });
+        Some(cons)
+    }
+    /// Distribute the audio to all clients until stopped
+    pub fn run(&self, cons: &mut RingCons, stop: &AtomicBool) {
+        let frame_bytes = self.format.frame_bytes();
+        let mut buf = vec![0u8; 65536 / frame_bytes * frame_bytes];
+        cons.set_timeout(Some(Duration::from_millis(100)));
+        while !stop.load(Ordering::Relaxed) {
+            match cons.wait_occupied(frame_bytes) {
+                Ok(()) => {}
+                Err(err) => match err {
+                    ringbuf_blocking::WaitError::Closed => break,
+                    ringbuf_blocking::WaitError::TimedOut => continue,
+                },
+            }
+            let len = cons.occupied_len().min(buf.len()) / frame_bytes * frame_bytes;
+            let len = cons.pop_slice(&mut buf[..len]);
+            self.clients.lock().unwrap().retain_mut(|client| {
+                if client.prod.is_closed() {
+                    return false;
+                }
+                // only push whole frames so skipping doesn't shift the channels
+                let fits = client.prod.vacant_len() / frame_bytes * frame_bytes;
+                let pushed = client.prod.push_slice(&buf[..len.min(fits)]);
+                if pushed == len {
+                    client.stalled_since = None;
+                    return true;
+                }
+                let since = *client.stalled_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= STALL_TIMEOUT {
+                    log::info!("dropping client {} (too slow)", client.addr);
+                    let _ = client.conn.shutdown(Shutdown::Both);
+                    return false;
+                }
+                log::debug!("client {} is too slow, skipping", client.addr);
+                true
+            });
+        }
+        for client in self.clients.lock().unwrap().drain(..) {
+            let _ = client.conn.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+pub struct Subscribers {
+    /// Format header to announce to new subscribers
+    header: [u8; crate::format::HEADER_LEN],
+    list: Mutex<Vec<(SocketAddr, Instant)>>,
+    /// Key that subscription requests must be sealed with
+    psk: Option<psk::Key>,
+    /// Seals everything sent to the subscribers, so it all belongs to one session
+    sealer: Option<psk::Sealer>,
+    acl: &'static Acl,
+}
+
+impl Subscribers {
+    pub fn new(
+        header: [u8; crate::format::HEADER_LEN],
+        psk: Option<psk::Key>,
+        acl: &'static Acl,
+    ) -> Self {
+        Self {
+            header,
+            list: Mutex::new(Vec::new()),
+            psk,
+            sealer: psk.map(|key| psk::Sealer::new(&key)),
+            acl,
+        }
+    }
+    pub fn sealer(&self) -> Option<&psk::Sealer> {
+        self.sealer.as_ref()
+    }
+    /// Handle subscription requests until stopped
+    pub fn listen(&self, sock: &UdpSocket, stop: &AtomicBool) {
+        if let Err(err) = sock.set_read_timeout(Some(Duration::from_millis(100))) {
+            log::error!("udp set timeout: {err}");
+            return;
+        }
+        let mut opener = self.psk.map(|key| psk::Opener::new(&key));
+        let mut buf = [0u8; 64];
+        while !stop.load(Ordering::Relaxed) {
+            let (len, addr) = match sock.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    log::error!("udp recv: {err}");
+                    break;
+                }
+            };
+            if !self.acl.allows(addr) {
+                continue;
+            }
+            let Some((_, data)) = psk::open(opener.as_mut(), &mut buf[..len]) else {
+                continue;
+            };
+            if !packet::is_subscribe(data) {
+                continue;
+            }
+            let mut list = self.list.lock().unwrap();
+            if let Some((_, seen)) = list.iter_mut().find(|(x, _)| *x == addr) {
+                *seen = Instant::now();
+                continue;
+            }
+            log::info!("udp subscriber {addr} joined");
+            // announce the format right away instead of making the subscriber wait for it
+            let mut sealed = [0u8; 64];
+            if let Ok(header) = psk::seal(self.sealer.as_ref(), &self.header, &mut sealed) {
+                let _ = sock.send_to(header, addr);
+            }
+            list.push((addr, Instant::now()));
+        }
+    }
+    /// Send a packet to every subscriber
+    pub fn send(&self, sock: &UdpSocket, data: &[u8]) {
+        self.list.lock().unwrap().retain(|(addr, seen)| {
+            if seen.elapsed() >= SUBSCRIPTION_TIMEOUT {
+                log::info!("udp subscriber {addr} timed out");
+                return false;
+            }
+            if let Err(err) = sock.send_to(data, addr) {
+                log::debug!("udp send to {addr}: {err}");
+            }
+            true
+        });
+    }
+}
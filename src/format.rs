@@ -0,0 +1,269 @@
+use std::fmt;
+
+use clap::{Args, ValueEnum};
+
+use crate::codec::Codec;
+
+/// Header magic, sent at the start of every TCP stream and as the UDP announce packet
+const MAGIC: [u8; 4] = *b"ihl\0";
+/// Bump this whenever the header layout changes
+const VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 13;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SampleFormat {
+    #[value(name = "s16le")]
+    S16LE,
+    /// Packed 24-bit samples
+    #[value(name = "s24le")]
+    S24LE,
+    #[value(name = "s32le")]
+    S32LE,
+    #[value(name = "f32le")]
+    F32LE,
+}
+
+impl SampleFormat {
+    fn to_raw(self) -> u8 {
+        match self {
+            Self::S16LE => 1,
+            Self::S24LE => 2,
+            Self::S32LE => 3,
+            Self::F32LE => 4,
+        }
+    }
+    fn from_raw(raw: u8) -> Option<Self> {
+        Some(match raw {
+            1 => Self::S16LE,
+            2 => Self::S24LE,
+            3 => Self::S32LE,
+            4 => Self::F32LE,
+            _ => return None,
+        })
+    }
+    /// Size of a single sample in bytes (24-bit samples are packed)
+    pub fn bytes(self) -> usize {
+        match self {
+            Self::S16LE => 2,
+            Self::S24LE => 3,
+            Self::S32LE | Self::F32LE => 4,
+        }
+    }
+    /// Decode a single sample into the -1.0..1.0 range
+    pub fn decode(self, buf: &[u8]) -> f32 {
+        match self {
+            Self::S16LE => f32::from(i16::from_le_bytes([buf[0], buf[1]])) / 32768.0,
+            Self::S24LE => {
+                (i32::from_le_bytes([0, buf[0], buf[1], buf[2]]) >> 8) as f32 / 8388608.0
+            }
+            Self::S32LE => {
+                i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f32 / 2147483648.0
+            }
+            Self::F32LE => f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+        }
+    }
+    /// Encode a single sample from the -1.0..1.0 range, clipping it if necessary
+    pub fn encode(self, x: f32, buf: &mut [u8]) {
+        match self {
+            // float to int casts saturate
+            Self::S16LE => buf[..2].copy_from_slice(&((x * 32768.0) as i16).to_le_bytes()),
+            Self::S24LE => buf[..3].copy_from_slice(
+                &((x * 8388608.0).clamp(-8388608.0, 8388607.0) as i32).to_le_bytes()[..3],
+            ),
+            Self::S32LE => buf[..4].copy_from_slice(&((x * 2147483648.0) as i32).to_le_bytes()),
+            Self::F32LE => buf[..4].copy_from_slice(&x.to_le_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::S16LE => "s16le",
+            Self::S24LE => "s24le",
+            Self::S32LE => "s32le",
+            Self::F32LE => "f32le",
+        })
+    }
+}
+
+#[derive(Args, Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StreamFormat {
+    /// Sample rate
+    #[arg(short, long, default_value_t = 48000)]
+    pub rate: u32,
+
+    /// Channel count
+    #[arg(short, long, default_value_t = 2)]
+    pub channels: u16,
+
+    /// Sample format
+    #[arg(short = 'f', long = "format", default_value_t = SampleFormat::S16LE)]
+    pub sample_format: SampleFormat,
+}
+
+impl fmt::Display for StreamFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}Hz {}ch",
+            self.sample_format, self.rate, self.channels
+        )
+    }
+}
+
+impl StreamFormat {
+    /// Size of a single frame (one sample for every channel) in bytes
+    pub fn frame_bytes(&self) -> usize {
+        self.sample_format.bytes() * usize::from(self.channels)
+    }
+    pub fn header(&self, codec: Codec) -> [u8; HEADER_LEN] {
+        let mut ret = [0u8; HEADER_LEN];
+        ret[..4].copy_from_slice(&MAGIC);
+        ret[4] = VERSION;
+        ret[5] = self.sample_format.to_raw();
+        ret[6..8].copy_from_slice(&self.channels.to_le_bytes());
+        ret[8..12].copy_from_slice(&self.rate.to_le_bytes());
+        ret[12] = codec.to_raw();
+        ret
+    }
+    pub fn is_header(buf: &[u8]) -> bool {
+        buf.len() == HEADER_LEN && buf[..4] == MAGIC
+    }
+    pub fn parse_header(buf: &[u8]) -> Result<(Self, Codec), HeaderError> {
+        if !Self::is_header(buf) {
+            return Err(HeaderError::BadMagic);
+        }
+        if buf[4] != VERSION {
+            return Err(HeaderError::Version(buf[4]));
+        }
+        Ok((
+            Self {
+                sample_format: SampleFormat::from_raw(buf[5])
+                    .ok_or(HeaderError::SampleFormat(buf[5]))?,
+                channels: u16::from_le_bytes([buf[6], buf[7]]),
+                rate: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            },
+            Codec::from_raw(buf[12]).ok_or(HeaderError::Codec(buf[12]))?,
+        ))
+    }
+    /// Parse a peer's header and make sure it matches our own format, returning the peer's codec
+    pub fn check_header(&self, buf: &[u8]) -> Result<Codec, HeaderError> {
+        let (theirs, codec) = Self::parse_header(buf)?;
+        if theirs != *self {
+            return Err(HeaderError::Mismatch {
+                ours: *self,
+                theirs,
+            });
+        }
+        Ok(codec)
+    }
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    BadMagic,
+    Version(u8),
+    SampleFormat(u8),
+    Codec(u8),
+    Mismatch {
+        ours: StreamFormat,
+        theirs: StreamFormat,
+    },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(
+                f,
+                "peer didn't send a header (use --raw for headerless streams)"
+            ),
+            Self::Version(ver) => write!(f, "peer uses protocol version {ver}, we use {VERSION}"),
+            Self::SampleFormat(fmt) => write!(f, "peer uses unknown sample format {fmt}"),
+            Self::Codec(codec) => write!(
+                f,
+                "peer uses codec {codec}, which isn't supported by this build"
+            ),
+            Self::Mismatch { ours, theirs } => {
+                write!(f, "peer streams {theirs}, but we expect {ours}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::S24LE,
+    };
+
+    #[test]
+    fn header_round_trip() {
+        let header = FORMAT.header(Codec::Pcm);
+        assert!(StreamFormat::is_header(&header));
+        assert!(matches!(
+            StreamFormat::parse_header(&header),
+            Ok((FORMAT, Codec::Pcm))
+        ));
+        assert!(matches!(FORMAT.check_header(&header), Ok(Codec::Pcm)));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let header = FORMAT.header(Codec::Pcm);
+        for len in 0..HEADER_LEN {
+            assert!(matches!(
+                FORMAT.check_header(&header[..len]),
+                Err(HeaderError::BadMagic)
+            ));
+        }
+        // raw audio that happens to be long enough
+        assert!(matches!(
+            FORMAT.check_header(&[0x55; HEADER_LEN]),
+            Err(HeaderError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let mut header = FORMAT.header(Codec::Pcm);
+        header[4] = VERSION + 1;
+        assert!(matches!(
+            FORMAT.check_header(&header),
+            Err(HeaderError::Version(_))
+        ));
+        let mut header = FORMAT.header(Codec::Pcm);
+        header[5] = 0xff;
+        assert!(matches!(
+            FORMAT.check_header(&header),
+            Err(HeaderError::SampleFormat(0xff))
+        ));
+        let mut header = FORMAT.header(Codec::Pcm);
+        header[12] = 0xff;
+        assert!(matches!(
+            FORMAT.check_header(&header),
+            Err(HeaderError::Codec(0xff))
+        ));
+    }
+
+    #[test]
+    fn rejects_mismatched_formats() {
+        let theirs = StreamFormat {
+            rate: 44100,
+            ..FORMAT
+        };
+        let err = FORMAT.check_header(&theirs.header(Codec::Pcm)).unwrap_err();
+        assert!(matches!(err, HeaderError::Mismatch { ours: FORMAT, .. }));
+        assert_eq!(
+            err.to_string(),
+            "peer streams s24le 44100Hz 2ch, but we expect s24le 48000Hz 2ch"
+        );
+    }
+}
@@ -0,0 +1,454 @@
+//! Simulated network trouble, for testing buffering and concealment without a bad network
+//!
+//! Everything we send goes through a fake link that delays it by a fixed amount plus some
+//! random jitter, limits its bandwidth and, for datagrams, loses, duplicates and reorders some of
+//! it. A stream (TCP) can't lose or reorder anything, so its lost segments are held back as if
+//! they got retransmitted instead, holding up everything behind them. The random choices come
+//! from a seeded generator, so a given setup misbehaves the same way every time.
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    str::FromStr,
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+/// How long a lost TCP segment takes to get retransmitted (Linux's minimum RTO)
+const RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+/// How long reordered datagrams are held back, so the ones after them overtake them
+const REORDER_DELAY: Duration = Duration::from_millis(20);
+/// Amount of data the bandwidth limit lets queue up (datagrams beyond it get dropped, streams
+/// get slowed down)
+const MAX_BACKLOG: Duration = Duration::from_millis(500);
+
+/// Shape of the random part of the delay
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Distribution {
+    /// Anywhere within the jitter of the delay
+    #[default]
+    Uniform,
+    /// Normally distributed, with the jitter as the standard deviation
+    Normal,
+    /// Only ever late, with rare but very late outliers, like on a busy wifi (the jitter is the
+    /// mean extra delay)
+    Pareto,
+}
+
+/// Everything that goes wrong on the simulated link, parsed from e.g.
+/// `delay=20ms,jitter=5ms,dist=normal,loss=1%,reorder=0.5%,dup=0.1%,rate=2mbit,seed=7`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Impairment {
+    delay: Duration,
+    jitter: Duration,
+    distribution: Distribution,
+    loss: f64,
+    reorder: f64,
+    duplicate: f64,
+    /// Bandwidth in bits per second
+    rate: Option<f64>,
+    seed: u64,
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        Self {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            distribution: Distribution::default(),
+            loss: 0.0,
+            reorder: 0.0,
+            duplicate: 0.0,
+            rate: None,
+            seed: 1,
+        }
+    }
+}
+
+impl FromStr for Impairment {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ret = Self::default();
+        for opt in s.split(',').filter(|x| !x.is_empty()) {
+            let (key, value) = opt
+                .split_once('=')
+                .ok_or_else(|| format!("expected <key>=<value>, got {opt:?}"))?;
+            match key {
+                "delay" => ret.delay = parse_duration(value)?,
+                "jitter" => ret.jitter = parse_duration(value)?,
+                "dist" => ret.distribution = Distribution::from_str(value, true)?,
+                "loss" => ret.loss = parse_percent(value)?,
+                "reorder" => ret.reorder = parse_percent(value)?,
+                "dup" => ret.duplicate = parse_percent(value)?,
+                "rate" => ret.rate = Some(parse_rate(value)?),
+                "seed" => {
+                    ret.seed = value
+                        .parse()
+                        .map_err(|err| format!("invalid seed {value:?}: {err}"))?
+                }
+                _ => return Err(format!("unknown impairment {key:?}")),
+            }
+        }
+        Ok(ret)
+    }
+}
+
+/// Parse e.g. `20ms` or `1.5s`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, scale) = if let Some(x) = s.strip_suffix("ms") {
+        (x, 1e-3)
+    } else if let Some(x) = s.strip_suffix('s') {
+        (x, 1.0)
+    } else {
+        return Err(format!("expected a duration in ms or s, got {s:?}"));
+    };
+    num.parse::<f64>()
+        .ok()
+        .and_then(|x| Duration::try_from_secs_f64(x * scale).ok())
+        .ok_or_else(|| format!("invalid duration {s:?}"))
+}
+
+/// Parse e.g. `1%` into a probability
+fn parse_percent(s: &str) -> Result<f64, String> {
+    s.strip_suffix('%')
+        .and_then(|x| x.parse::<f64>().ok())
+        .filter(|x| (0.0..=100.0).contains(x))
+        .map(|x| x / 100.0)
+        .ok_or_else(|| format!("expected a percentage, got {s:?}"))
+}
+
+/// Parse e.g. `500kbit` or `2mbit` into bits per second
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let (num, scale) = if let Some(x) = s.strip_suffix("mbit") {
+        (x, 1e6)
+    } else if let Some(x) = s.strip_suffix("kbit") {
+        (x, 1e3)
+    } else if let Some(x) = s.strip_suffix("bit") {
+        (x, 1.0)
+    } else {
+        return Err(format!("expected a rate in bit, kbit or mbit, got {s:?}"));
+    };
+    num.parse::<f64>()
+        .ok()
+        .map(|x| x * scale)
+        .filter(|x| *x > 0.0)
+        .ok_or_else(|| format!("invalid rate {s:?}"))
+}
+
+impl Impairment {
+    /// Run `f` with a function that sends data through `send` as if it went over the impaired
+    /// link, then wait for whatever is still on the link to arrive
+    ///
+    /// `stream` is whether the data is a byte stream rather than datagrams.
+    pub fn run<T>(
+        &self,
+        stream: bool,
+        send: impl FnMut(&[u8]) -> io::Result<()> + Send,
+        f: impl FnOnce(&mut (dyn FnMut(&[u8]) -> io::Result<()> + Send)) -> T,
+    ) -> T {
+        let failed = Mutex::new(None);
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|s| {
+            let failed = &failed;
+            s.spawn(move || deliver(rx, send, failed));
+            let now = Instant::now();
+            let mut link = Link {
+                impairment: *self,
+                stream,
+                rng: Rng::new(self.seed),
+                tx,
+                failed,
+                seq: 0,
+                idle_at: now,
+                last_due: now,
+            };
+            f(&mut |data| link.send(data))
+        })
+    }
+}
+
+/// Sending side of the simulated link
+struct Link<'a> {
+    impairment: Impairment,
+    stream: bool,
+    rng: Rng,
+    tx: mpsc::Sender<Queued>,
+    /// Error that stopped the delivery thread
+    failed: &'a Mutex<Option<io::Error>>,
+    seq: u64,
+    /// When the bandwidth limited part of the link is done with everything sent so far
+    idle_at: Instant,
+    /// When the last in-order data arrives
+    last_due: Instant,
+}
+
+impl Link<'_> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(err) = self.failed.lock().unwrap().take() {
+            return Err(err);
+        }
+        let imp = self.impairment;
+        let now = Instant::now();
+        let mut sent = now;
+        if let Some(rate) = imp.rate {
+            let start = self.idle_at.max(now);
+            let backlog = start - now;
+            if backlog > MAX_BACKLOG {
+                if !self.stream {
+                    return Ok(());
+                }
+                // TCP's congestion control would have slowed the sender down by now
+                std::thread::sleep(backlog - MAX_BACKLOG);
+            }
+            self.idle_at = start + Duration::from_secs_f64(data.len() as f64 * 8.0 / rate);
+            sent = self.idle_at;
+        }
+        let mut due = sent + self.delay();
+        if self.rng.chance(imp.loss) {
+            if !self.stream {
+                return Ok(());
+            }
+            due += RETRANSMIT_DELAY;
+        }
+        // datagrams mostly queue up behind each other too, rather than overtaking
+        if self.stream || !self.rng.chance(imp.reorder) {
+            due = due.max(self.last_due);
+            self.last_due = due;
+        } else {
+            due += REORDER_DELAY;
+        }
+        self.queue(due, data)?;
+        if !self.stream && self.rng.chance(imp.duplicate) {
+            self.queue(due, data)?;
+        }
+        Ok(())
+    }
+    /// One-way delay for the next bit of data
+    fn delay(&mut self) -> Duration {
+        let imp = self.impairment;
+        let jitter = imp.jitter.as_secs_f64();
+        let offset = match imp.distribution {
+            Distribution::Uniform => (self.rng.next() * 2.0 - 1.0) * jitter,
+            Distribution::Normal => self.rng.normal() * jitter,
+            // how far Pareto with shape 3 and scale 2 * jitter exceeds its scale, on average jitter
+            Distribution::Pareto => 2.0 * jitter * ((1.0 - self.rng.next()).powf(-1.0 / 3.0) - 1.0),
+        };
+        Duration::from_secs_f64((imp.delay.as_secs_f64() + offset).max(0.0))
+    }
+    fn queue(&mut self, due: Instant, data: &[u8]) -> io::Result<()> {
+        self.seq += 1;
+        let queued = Queued {
+            due,
+            seq: self.seq,
+            data: data.to_vec(),
+        };
+        self.tx.send(queued).map_err(|_| {
+            self.failed
+                .lock()
+                .unwrap()
+                .take()
+                .unwrap_or_else(|| io::Error::other("impaired link closed"))
+        })
+    }
+}
+
+/// Data on its way through the link
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Queued {
+    due: Instant,
+    /// Keeps data that's due at the same time in order
+    seq: u64,
+    data: Vec<u8>,
+}
+
+/// Pass the data on as it becomes due, until the sending side is gone and everything arrived
+fn deliver(
+    rx: mpsc::Receiver<Queued>,
+    mut send: impl FnMut(&[u8]) -> io::Result<()>,
+    failed: &Mutex<Option<io::Error>>,
+) {
+    let mut queue = BinaryHeap::new();
+    let mut open = true;
+    while open || !queue.is_empty() {
+        let wait = queue
+            .peek()
+            .map(|x: &Reverse<Queued>| x.0.due.saturating_duration_since(Instant::now()));
+        if open {
+            let res = match wait {
+                Some(wait) => rx.recv_timeout(wait),
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match res {
+                Ok(x) => queue.push(Reverse(x)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => open = false,
+            }
+        } else if let Some(wait) = wait {
+            std::thread::sleep(wait);
+        }
+        while queue.peek().is_some_and(|x| x.0.due <= Instant::now()) {
+            let Reverse(x) = queue.pop().unwrap();
+            if let Err(err) = send(&x.data) {
+                *failed.lock().unwrap() = Some(err);
+                return;
+            }
+        }
+    }
+}
+
+/// Small xorshift generator, plenty random for picking what goes wrong
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck at 0
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+    /// Uniformly distributed in [0, 1)
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+    /// Standard normally distributed
+    fn normal(&mut self) -> f64 {
+        let (a, b) = (1.0 - self.next(), self.next());
+        (-2.0 * a.ln()).sqrt() * (std::f64::consts::TAU * b).cos()
+    }
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn impairment(s: &str) -> Impairment {
+        s.parse().unwrap()
+    }
+
+    /// Send `count` numbered packets through a link impaired by `imp`, returning the numbers
+    /// that arrived
+    fn deliveries(imp: &str, stream: bool, count: u32) -> Vec<u32> {
+        let mut arrived = Vec::new();
+        let send = |data: &[u8]| {
+            arrived.push(u32::from_le_bytes(data.try_into().unwrap()));
+            Ok(())
+        };
+        impairment(imp).run(stream, send, |send| {
+            for i in 0..count {
+                send(&i.to_le_bytes()).unwrap();
+            }
+        });
+        arrived
+    }
+
+    #[test]
+    fn parses_impairments() {
+        let imp = impairment(
+            "delay=20ms,jitter=1.5s,dist=normal,loss=1%,reorder=0.5%,dup=100%,rate=2mbit,seed=7",
+        );
+        assert_eq!(
+            imp,
+            Impairment {
+                delay: Duration::from_millis(20),
+                jitter: Duration::from_millis(1500),
+                distribution: Distribution::Normal,
+                loss: 0.01,
+                reorder: 0.005,
+                duplicate: 1.0,
+                rate: Some(2e6),
+                seed: 7,
+            }
+        );
+        assert_eq!(impairment(""), Impairment::default());
+        assert_eq!(impairment("rate=500kbit").rate, Some(5e5));
+        assert_eq!(impairment("rate=64bit").rate, Some(64.0));
+        assert_eq!(impairment("dist=PARETO").distribution, Distribution::Pareto);
+    }
+
+    #[test]
+    fn rejects_bad_impairments() {
+        for bad in [
+            "delay",
+            "delay=20",
+            "delay=-5ms",
+            "jitter=fast",
+            "loss=1",
+            "loss=101%",
+            "loss=-1%",
+            "dup=%",
+            "rate=2",
+            "rate=0mbit",
+            "rate=1gbit",
+            "dist=gaussian",
+            "seed=-1",
+            "latency=20ms",
+        ] {
+            assert!(bad.parse::<Impairment>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn misbehaves_the_same_way_every_time() {
+        // which packets get lost and duplicated, that is (the order depends on timing)
+        let sorted = |imp| {
+            let mut arrived = deliveries(imp, false, 200);
+            arrived.sort();
+            arrived
+        };
+        let imp = "jitter=1ms,loss=20%,reorder=10%,dup=10%,seed=3";
+        assert_eq!(sorted(imp), sorted(imp));
+        assert_ne!(
+            sorted(imp),
+            sorted("jitter=1ms,loss=20%,reorder=10%,dup=10%,seed=4")
+        );
+    }
+
+    #[test]
+    fn loses_some_datagrams() {
+        let arrived = deliveries("loss=25%", false, 2000);
+        assert!(
+            (1400..1600).contains(&arrived.len()),
+            "{} arrived",
+            arrived.len()
+        );
+        assert!(arrived.is_sorted());
+        assert!(deliveries("loss=100%", false, 100).is_empty());
+    }
+
+    #[test]
+    fn duplicates_datagrams_right_away() {
+        let arrived = deliveries("dup=100%", false, 100);
+        let twice: Vec<u32> = (0..100).flat_map(|x| [x, x]).collect();
+        assert_eq!(arrived, twice);
+    }
+
+    #[test]
+    fn reordered_datagrams_get_overtaken() {
+        let arrived = deliveries("reorder=50%", false, 100);
+        let mut sorted = arrived.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..100).collect::<Vec<_>>());
+        let overtaken = arrived
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| arrived[..*i].iter().any(|y| y > x))
+            .count();
+        assert!((25..75).contains(&overtaken), "{overtaken} got overtaken");
+    }
+
+    #[test]
+    fn streams_retransmit_instead_of_losing() {
+        let arrived = deliveries("loss=50%,reorder=50%,dup=50%", true, 100);
+        assert_eq!(arrived, (0..100).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,372 @@
+//! Adaptive jitter buffer
+//!
+//! The network thread timestamps every bit of incoming audio and compares it with when it should
+//! have arrived given the stream's sample rate. The spread of these delays over the last few
+//! seconds tells how much audio needs to be buffered so that only the given percentage of it
+//! arrives too late, which becomes the target buffer size of the player. The player then keeps
+//! its buffer at the target, dropping excess audio when the network calms down, and buffering up
+//! to the (now larger) target again after running dry. Small slow deviations from the target are
+//! due to the sender's and receiver's clocks drifting apart, and get resampled away instead.
+
+use std::{
+    collections::VecDeque,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::format::StreamFormat;
+
+/// How long to remember arrival delays for
+const WINDOW: Duration = Duration::from_secs(5);
+/// How often to recompute the target
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// Delays jumping by more than this mean the stream restarted
+const MAX_JUMP_SEC: f64 = 1.0;
+const INITIAL_TARGET_MS: usize = 20;
+const MAX_TARGET_MS: usize = 500;
+/// Buffer level errors bigger than this are dropped instead of resampled away
+const MAX_DRIFT_ERROR_SEC: f64 = 0.05;
+/// How fast to resample buffer level errors away
+const DRIFT_CORRECTION_SEC: f64 = 10.0;
+/// How slowly the drift estimate follows the buffer level error
+const DRIFT_INTEGRAL_SEC: f64 = 100.0;
+/// Audio gets stretched when the buffer gets this close to running dry
+const LOW_WATER_MS: usize = 5;
+/// Max amount of audio to stretch per window
+const MAX_STRETCH_MS: usize = 40;
+
+/// Target buffer size, shared between the network thread and the player
+#[derive(Debug)]
+pub struct Target {
+    frames: AtomicUsize,
+    /// Percentage of audio that may arrive too late
+    late_pct: f32,
+}
+
+impl Target {
+    pub fn new(format: StreamFormat, late_pct: f32) -> Self {
+        Self {
+            frames: AtomicUsize::new(format.rate as usize * INITIAL_TARGET_MS / 1000),
+            late_pct: late_pct.clamp(0.0, 100.0),
+        }
+    }
+    /// Target size in frames
+    pub fn frames(&self) -> usize {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
+
+/// Network side of the jitter buffer
+pub struct Estimator {
+    target: Option<&'static Target>,
+    format: StreamFormat,
+    start: Option<Instant>,
+    /// Amount of bytes received so far
+    received: u64,
+    /// Recent arrival times with their delays in seconds
+    delays: VecDeque<(Instant, f64)>,
+    min_delay: f64,
+    last_update: Instant,
+    sorted: Vec<f64>,
+}
+
+impl Estimator {
+    /// Create an estimator updating `target`, or doing nothing if it's `None`
+    pub fn new(target: Option<&'static Target>, format: StreamFormat) -> Self {
+        Self {
+            target,
+            format,
+            start: None,
+            received: 0,
+            delays: VecDeque::new(),
+            min_delay: 0.0,
+            last_update: Instant::now(),
+            sorted: Vec::new(),
+        }
+    }
+    /// Must be called whenever audio arrives, before it's pushed into the ring buffer
+    pub fn arrived(&mut self, bytes: usize) {
+        let Some(target) = self.target else {
+            return;
+        };
+        let now = Instant::now();
+        let start = *self.start.get_or_insert(now);
+        let bytes_per_sec = self.format.rate as f64 * self.format.frame_bytes() as f64;
+        let delay = now.duration_since(start).as_secs_f64() - self.received as f64 / bytes_per_sec;
+        if !self.delays.is_empty() && (delay - self.min_delay).abs() > MAX_JUMP_SEC {
+            log::debug!("stream timing jumped, restarting jitter estimation");
+            self.start = Some(now);
+            self.received = 0;
+            self.delays.clear();
+            return self.arrived(bytes);
+        }
+        self.received += bytes as u64;
+        while self
+            .delays
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) > WINDOW)
+        {
+            self.delays.pop_front();
+        }
+        self.delays.push_back((now, delay));
+        if self.delays.len() == 1 {
+            self.min_delay = delay;
+        }
+        self.min_delay = self.min_delay.min(delay);
+        if self.last_update.elapsed() >= UPDATE_INTERVAL {
+            self.last_update = now;
+            self.update(target);
+        }
+    }
+    fn update(&mut self, target: &Target) {
+        // the earliest arrival is the reference point everything else is late relative to
+        self.min_delay = self
+            .delays
+            .iter()
+            .map(|(_, delay)| *delay)
+            .fold(f64::INFINITY, f64::min);
+        self.sorted.clear();
+        self.sorted
+            .extend(self.delays.iter().map(|(_, delay)| delay - self.min_delay));
+        self.sorted.sort_by(f64::total_cmp);
+        let idx = (self.sorted.len() - 1) as f32 * (1.0 - target.late_pct / 100.0);
+        let rate = self.format.rate as usize;
+        let frames = ((self.sorted[idx.round() as usize] * rate as f64) as usize)
+            .min(rate * MAX_TARGET_MS / 1000);
+        let old = target.frames.swap(frames, Ordering::Relaxed);
+        if old != frames {
+            log::trace!("jitter buffer target {frames} frames");
+        }
+    }
+}
+
+/// What the player should do in a callback
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    Play,
+    /// Play concealment while buffering up
+    Conceal,
+    /// Drop this many frames before playing
+    Drop(usize),
+    /// Insert this many frames before playing
+    Insert(usize),
+}
+
+/// Player side of the jitter buffer
+pub struct Playout {
+    target: &'static Target,
+    /// Fixed target in frames, disables adaptation
+    fixed: Option<usize>,
+    rate: f64,
+    prebuffering: bool,
+    /// Length of the window the buffer level is observed over, in frames
+    window: usize,
+    elapsed: usize,
+    min_extra: usize,
+    low_water: usize,
+    /// Frames that may still be inserted in this window
+    stretch_left: usize,
+    max_stretch: usize,
+    /// Max deviation of the resampling ratio from 1
+    max_drift: f64,
+    /// Resampling ratio (input frames per output frame)
+    ratio: f64,
+    /// Accumulated buffer level error, which converges to the drift
+    integral: f64,
+}
+
+impl Playout {
+    pub fn new(
+        target: &'static Target,
+        fixed: Option<usize>,
+        format: StreamFormat,
+        max_drift_ppm: u32,
+    ) -> Self {
+        Self {
+            target,
+            fixed,
+            rate: format.rate.into(),
+            prebuffering: true,
+            window: format.rate as usize,
+            elapsed: 0,
+            min_extra: usize::MAX,
+            low_water: format.rate as usize * LOW_WATER_MS / 1000,
+            stretch_left: 0,
+            max_stretch: format.rate as usize * MAX_STRETCH_MS / 1000,
+            max_drift: f64::from(max_drift_ppm) / 1e6,
+            ratio: 1.0,
+            integral: 0.0,
+        }
+    }
+    /// Resampling ratio to play with (input frames per output frame)
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+    /// Must be called at the start of every callback, with the amount of buffered frames and
+    /// the amount of frames the callback needs
+    ///
+    /// `Insert` and `Drop` are best effort, call `inserted` with the amount actually inserted.
+    pub fn next(&mut self, buffered: usize, needed: usize) -> Action {
+        let extra = buffered.saturating_sub(needed);
+        let target = self.fixed.unwrap_or_else(|| self.target.frames());
+        if self.prebuffering {
+            if extra < target {
+                return Action::Conceal;
+            }
+            log::debug!("buffered up {target} frames");
+            self.prebuffering = false;
+            self.reset_window();
+        }
+        if buffered < needed {
+            // play whatever is left, then buffer up again
+            self.prebuffering = true;
+            return Action::Play;
+        }
+        self.min_extra = self.min_extra.min(extra);
+        self.elapsed += needed;
+        if self.elapsed < self.window {
+            // stretch the audio a bit if it's about to run out, in case more is on its way
+            if extra < self.low_water.min(target) && self.stretch_left > 0 {
+                return Action::Insert((target - extra).min(self.stretch_left));
+            }
+            return Action::Play;
+        }
+        // the buffer never went below this over the whole window, so that's the real level
+        let error = self.min_extra as f64 - target as f64;
+        self.reset_window();
+        if self.max_drift > 0.0 && error < MAX_DRIFT_ERROR_SEC * self.rate {
+            self.correct_drift(error / self.rate);
+            return Action::Play;
+        }
+        if error < 1.0 {
+            return Action::Play;
+        }
+        log::debug!("dropping {error} frames to get down to {target}");
+        Action::Drop(error as usize)
+    }
+    /// Must be called after acting on `Action::Insert`
+    pub fn inserted(&mut self, frames: usize) {
+        self.stretch_left = self.stretch_left.saturating_sub(frames);
+    }
+    fn reset_window(&mut self) {
+        self.elapsed = 0;
+        self.min_extra = usize::MAX;
+        self.stretch_left = self.max_stretch;
+    }
+    /// Adjust the resampling ratio to bring the buffer level back to the target
+    fn correct_drift(&mut self, error_sec: f64) {
+        self.integral =
+            (self.integral + error_sec / DRIFT_INTEGRAL_SEC).clamp(-self.max_drift, self.max_drift);
+        self.ratio = 1.0
+            + (self.integral + error_sec / DRIFT_CORRECTION_SEC)
+                .clamp(-self.max_drift, self.max_drift);
+        log::trace!(
+            "buffer level error {:.1}ms, drift {:.0}ppm, resampling at {:.0}ppm",
+            error_sec * 1000.0,
+            self.integral * 1e6,
+            (self.ratio - 1.0) * 1e6,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::S16LE,
+    };
+    /// Frames the player needs per callback (10ms)
+    const NEEDED: usize = 480;
+
+    fn target(late_pct: f32) -> &'static Target {
+        Box::leak(Box::new(Target::new(FORMAT, late_pct)))
+    }
+
+    /// Run the player for `secs` seconds against a sender whose clock is `drift` faster,
+    /// returning how much audio is buffered beyond what the next callback needs
+    fn simulate(playout: &mut Playout, buffered: &mut f64, drift: f64, secs: usize) -> f64 {
+        for _ in 0..secs * FORMAT.rate as usize / NEEDED {
+            *buffered += NEEDED as f64 * (1.0 + drift);
+            let played = match playout.next(*buffered as usize, NEEDED) {
+                Action::Play => NEEDED as f64 * playout.ratio(),
+                Action::Conceal => 0.0,
+                Action::Drop(frames) => (frames + NEEDED) as f64,
+                Action::Insert(frames) => {
+                    playout.inserted(frames);
+                    NEEDED.saturating_sub(frames) as f64
+                }
+            };
+            *buffered = (*buffered - played).max(0.0);
+        }
+        *buffered
+    }
+
+    #[test]
+    fn target_covers_all_but_the_latest_arrivals() {
+        let target = target(1.0);
+        let mut estimator = Estimator::new(Some(target), FORMAT);
+        let now = Instant::now();
+        // arrivals up to 100ms late, evenly spread
+        estimator
+            .delays
+            .extend((0..=100).map(|ms| (now, ms as f64 / 1000.0)));
+        estimator.update(target);
+        assert_eq!(target.frames(), 99 * FORMAT.rate as usize / 1000);
+        // but never more than the max, even when arrivals are up to a second late
+        estimator.delays.clear();
+        estimator
+            .delays
+            .extend((0..=100).map(|ms| (now, ms as f64 / 100.0)));
+        estimator.update(target);
+        assert_eq!(target.frames(), FORMAT.rate as usize * MAX_TARGET_MS / 1000);
+    }
+
+    #[test]
+    fn prebuffers_up_to_the_target() {
+        let mut playout = Playout::new(target(1.0), Some(960), FORMAT, 500);
+        assert_eq!(playout.next(NEEDED, NEEDED), Action::Conceal);
+        assert_eq!(playout.next(NEEDED + 959, NEEDED), Action::Conceal);
+        assert_eq!(playout.next(NEEDED + 960, NEEDED), Action::Play);
+        // running dry means buffering up again
+        assert_eq!(playout.next(NEEDED - 1, NEEDED), Action::Play);
+        assert_eq!(playout.next(NEEDED, NEEDED), Action::Conceal);
+    }
+
+    #[test]
+    fn resampling_converges_on_the_clock_drift() {
+        let fixed = 960;
+        let mut playout = Playout::new(target(1.0), Some(fixed), FORMAT, 500);
+        let drift = 200e-6;
+        let mut buffered = 0.0;
+        simulate(&mut playout, &mut buffered, drift, 1000);
+        assert!(
+            (playout.ratio() - 1.0 - drift).abs() < 10e-6,
+            "resampling at {:.0}ppm",
+            (playout.ratio() - 1.0) * 1e6
+        );
+        let extra = simulate(&mut playout, &mut buffered, drift, 10);
+        assert!(
+            (extra - fixed as f64).abs() < 48.0,
+            "{extra} frames buffered instead of {fixed}"
+        );
+    }
+
+    #[test]
+    fn excess_audio_gets_dropped() {
+        let fixed = 960;
+        let mut playout = Playout::new(target(1.0), Some(fixed), FORMAT, 500);
+        let mut buffered = 0.0;
+        simulate(&mut playout, &mut buffered, 0.0, 2);
+        // a burst of audio, far more than resampling could get rid of
+        buffered += FORMAT.rate as f64;
+        let extra = simulate(&mut playout, &mut buffered, 0.0, 3);
+        assert!(
+            (extra - fixed as f64).abs() < 48.0,
+            "{extra} frames buffered instead of {fixed}"
+        );
+    }
+}
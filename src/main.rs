@@ -1,22 +1,81 @@
 #![allow(clippy::blocks_in_conditions)]
 use std::{
+    collections::HashMap,
     io::{Read, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
-    sync::Arc,
-    time::Duration,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use clap::{Args, Parser, Subcommand};
-use ringbuf::traits::{Consumer, Producer, Split};
+use acl::{Acl, Cidr};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
 use ringbuf_blocking::{BlockingHeapRb, BlockingRb};
 
+use codec::{CodecOpts, Decoder, Encoder};
+use conceal::{Concealer, Concealment};
+use fanout::{Fanout, Subscribers};
+use format::StreamFormat;
+use impair::Impairment;
+use jitter::Estimator;
+use mdns::{Address, MdnsOpts, Role};
+use mix::{Mixer, SourceGain};
+use multicast::MulticastOpts;
+#[cfg(feature = "jack")]
+use record_jack::JackOpts;
+
+mod acl;
+mod codec;
+mod conceal;
+mod device;
+mod fanout;
+mod format;
+mod impair;
+mod jitter;
+mod mdns;
+mod mix;
+mod multicast;
+mod packet;
 mod play;
+mod play_file;
+#[cfg(feature = "jack")]
+mod play_jack;
+#[cfg(feature = "pipewire")]
+mod play_pipewire;
+mod psk;
+#[cfg(feature = "pipewire")]
 mod record;
+#[cfg(feature = "alsa")]
+mod record_alsa;
+mod record_cpal;
+mod record_file;
+#[cfg(feature = "jack")]
+mod record_jack;
+#[cfg(feature = "pulse")]
+mod record_pulse;
+mod resample;
+mod rtp;
+mod splice;
+mod wav;
 
 type RingBuf = Arc<BlockingHeapRb<u8>>;
 type RingProd = ringbuf_blocking::BlockingProd<RingBuf>;
 type RingCons = ringbuf_blocking::BlockingCons<RingBuf>;
 
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// A peer that hangs up on a fresh TCP connection this quickly rejected our stream
+const MIN_SESSION: Duration = Duration::from_secs(1);
+/// Longest we wait before reconnecting to a peer that keeps rejecting us
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Forget quiet senders after this long when mixing without an inactivity timeout
+const MIX_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// A UDP stream only gets taken over by another sender's session after going quiet this long
+const SESSION_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 struct Cli {
@@ -26,11 +85,44 @@ struct Cli {
     #[arg(short, long)]
     inactivity_sec: Option<u32>,
 
+    /// Simulate a bad network for the stream we record (e.g. delay=20ms,jitter=5ms,loss=1%)
+    #[arg(long, hide = true)]
+    impair: Option<Impairment>,
+
+    /// File holding a secret shared with the other side, for encrypting the stream and rejecting
+    /// peers that don't have it
+    #[arg(long, conflicts_with_all = ["raw", "rtp"])]
+    psk_file: Option<PathBuf>,
+
+    /// Write an SDP file describing the RTP stream, for other programs to open
+    #[arg(long, value_name = "PATH", requires = "rtp")]
+    sdp: Option<PathBuf>,
+
+    /// Only accept peers from this network (e.g. 192.168.1.0/24, may be passed multiple times)
+    #[arg(long, value_name = "CIDR")]
+    allow: Vec<Cidr>,
+
+    /// Reject peers from this network, even if allowed by --allow (may be passed multiple times)
+    #[arg(long, value_name = "CIDR")]
+    deny: Vec<Cidr>,
+
     #[command(flatten)]
     net: Endpoint,
 
     #[command(subcommand)]
-    command: Cmd,
+    command: Command,
+}
+
+#[derive(Clone, Debug, Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Stream(Cmd),
+    /// List the listening instances advertised on the local network
+    Discover {
+        /// How long to wait for answers
+        #[arg(long, default_value_t = 2)]
+        wait_sec: u32,
+    },
 }
 
 #[derive(Clone, Debug, Subcommand)]
@@ -39,21 +131,164 @@ enum Cmd {
         /// Amount of samples to buffer (disables automatic buffer adjustment)
         #[arg(short = 's', long)]
         buffer_samples: Option<usize>,
+        /// Percentage of audio that may arrive too late to be played
+        ///
+        /// The jitter buffer is sized to fit the network jitter of the rest, so lower values mean
+        /// fewer dropouts but more latency.
+        #[arg(long, default_value_t = 1.0)]
+        late_pct: f32,
+        /// Max clock drift between the sender and us to compensate for by resampling (0 disables
+        /// resampling)
+        #[arg(long, default_value_t = 500)]
+        max_drift_ppm: u32,
+        /// Where to play to
+        #[arg(long, value_enum, default_value_t = PlayBackend::Cpal)]
+        backend: PlayBackend,
+        /// Device (cpal backend) or sink node (pipewire backend) to play to
         #[arg(short, long)]
         device_name: Option<String>,
+        /// Create a source called this for apps to record from, instead of playing to a sink
+        /// (pipewire backend)
+        #[cfg(feature = "pipewire")]
+        #[arg(long, value_name = "NAME", conflicts_with = "device_name")]
+        as_source: Option<String>,
+        #[cfg(feature = "jack")]
+        #[command(flatten)]
+        jack: JackOpts,
+        /// File to write to, as WAV if its name ends with .wav (file backend)
+        #[arg(long, required_if_eq("backend", "file"))]
+        file: Option<PathBuf>,
+        /// What to play in place of missing audio
+        #[arg(long, value_enum, default_value_t = Concealment::Fade)]
+        conceal: Concealment,
+        /// Codec of incoming RTP streams (other streams announce their codec)
+        #[arg(long, value_enum)]
+        codec: Option<codec::Codec>,
+        /// Accept any number of streams at once and mix them together (requires --listen)
+        #[arg(long)]
+        mix: bool,
+        /// Gain for the streams coming from an IP address when mixing (e.g. 192.168.1.5=0.5)
+        #[arg(long, value_name = "IP=GAIN", requires = "mix")]
+        gain: Vec<SourceGain>,
+        #[command(flatten)]
+        format: StreamFormat,
     },
     Record {
+        /// Where to record from
+        #[arg(long, value_enum, default_value_t = RecordBackend::default())]
+        backend: RecordBackend,
+        /// Node to record from (pipewire backend)
         #[arg(short, long)]
-        node_name: String,
+        node_name: Option<String>,
+        /// Create a sink called --node-name for apps to play to, instead of recording from an
+        /// existing node (pipewire backend)
+        #[arg(long, requires = "node_name")]
+        virtual_sink: bool,
+        /// Device (alsa and cpal backends) or source (pulse backend) to record from
+        #[arg(short, long, default_value = "default")]
+        device: String,
+        #[cfg(feature = "jack")]
+        #[command(flatten)]
+        jack: JackOpts,
+        /// Raw or WAV file to send (file backend)
+        #[arg(long, required_if_eq("backend", "file"))]
+        file: Option<PathBuf>,
+        #[command(flatten)]
+        format: StreamFormat,
+        #[command(flatten)]
+        codec: CodecOpts,
     },
 }
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum PlayBackend {
+    Cpal,
+    #[cfg(feature = "pipewire")]
+    Pipewire,
+    #[cfg(feature = "jack")]
+    Jack,
+    File,
+    Stdout,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum RecordBackend {
+    #[cfg(feature = "pipewire")]
+    Pipewire,
+    #[cfg(feature = "alsa")]
+    Alsa,
+    #[cfg(feature = "pulse")]
+    Pulse,
+    #[cfg(feature = "jack")]
+    Jack,
+    Cpal,
+    File,
+    Stdin,
+}
+
+impl Default for RecordBackend {
+    fn default() -> Self {
+        #[cfg(feature = "pipewire")]
+        return Self::Pipewire;
+        #[cfg(all(not(feature = "pipewire"), feature = "pulse"))]
+        return Self::Pulse;
+        #[cfg(all(not(any(feature = "pipewire", feature = "pulse")), feature = "alsa"))]
+        return Self::Alsa;
+        #[cfg(not(any(feature = "pipewire", feature = "pulse", feature = "alsa")))]
+        return Self::Cpal;
+    }
+}
+
+/// Per-connection settings shared by all transports
+#[derive(Copy, Clone, Debug)]
+struct Opts {
+    inactivity_sec: u32,
+    format: StreamFormat,
+    /// Don't send or expect the format header
+    raw: bool,
+    /// Send and expect RTP packets instead of ours
+    rtp: bool,
+    conceal: Concealment,
+    codec: CodecOpts,
+    /// Jitter buffer target to update with the arrival times of received audio
+    jitter: Option<&'static jitter::Target>,
+    /// Simulated network trouble for the data we send
+    impair: Option<Impairment>,
+    /// Pre-shared key to encrypt and authenticate the stream with
+    psk: Option<psk::Key>,
+    /// Peers to accept connections and datagrams from
+    acl: &'static Acl,
+}
+
+/// The handshake with the peer failed, so the stream never got going
+#[derive(Debug)]
+struct Rejected;
+
 trait ProdCons {
-    fn produce(&mut self, prod: &mut RingProd, inactivity_sec: u32);
-    fn consume(&mut self, cons: &mut RingCons, inactivity_sec: u32);
+    fn produce(&mut self, prod: &mut RingProd, opts: &Opts) -> Result<(), Rejected>;
+    fn consume(&mut self, cons: &mut RingCons, opts: &Opts) -> Result<(), Rejected>;
+}
+
+/// Delay before reconnecting after being rejected, doubling while the peer keeps rejecting us
+#[derive(Debug, Default)]
+struct Backoff(Option<Duration>);
+
+impl Backoff {
+    fn ended(&mut self, res: Result<(), Rejected>) {
+        if res.is_ok() {
+            self.0 = None;
+            return;
+        }
+        let delay = self
+            .0
+            .map_or(Duration::from_secs(1), |x| (x * 2).min(MAX_BACKOFF));
+        log::info!("reconnecting in {}s", delay.as_secs());
+        std::thread::sleep(delay);
+        self.0 = Some(delay);
+    }
 }
 
-#[derive(Args, Copy, Clone, Debug)]
+#[derive(Args, Clone, Debug)]
 struct Endpoint {
     /// Whether to listen for connections instead of connecting to the address
     #[arg(short, long)]
@@ -63,24 +298,74 @@ struct Endpoint {
     #[arg(short, long)]
     udp: bool,
 
-    /// Connect/bind address
+    /// Send/expect headerless PCM (for interop with other programs)
+    ///
+    /// The stream format isn't checked in this mode, so both sides must agree on it beforehand
+    #[arg(long)]
+    raw: bool,
+
+    /// Send/expect RTP (for interop with ffmpeg, GStreamer, VLC, PipeWire's RTP modules, ...)
+    ///
+    /// The stream format isn't sent in this mode either, so both sides must agree on it
+    /// beforehand (see --sdp). Recorders have to connect to a listening player.
+    #[arg(long, requires = "udp", conflicts_with = "raw")]
+    rtp: bool,
+
+    /// Connect/bind address, or mdns:<name> to connect to an instance advertised as <name>
+    ///
+    /// With UDP, this may be a multicast group for a connecting recorder to send to, and any
+    /// number of listening players to join.
     #[arg(short, long)]
-    address: SocketAddr,
+    address: Option<Address>,
+
+    #[command(flatten)]
+    multicast: MulticastOpts,
+
+    #[command(flatten)]
+    mdns: MdnsOpts,
 }
 
 impl Endpoint {
-    fn bind_udp(&self) -> Option<UdpSocket> {
-        match std::net::UdpSocket::bind(if self.listen {
-            self.address
-        } else {
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
-        })
-        .and_then(|sock| {
-            if !self.listen {
-                sock.connect(self.address)?;
+    /// Address to bind or connect to, looking mDNS names up again every time in case the peer
+    /// moved (`role` is what the peer has to do)
+    fn addr(&self, role: Role) -> Option<SocketAddr> {
+        let name = match &self.address {
+            Some(Address::Ip(addr)) => return Some(*addr),
+            Some(Address::Mdns(name)) => name,
+            // checked in main
+            None => unreachable!(),
+        };
+        match mdns::resolve(name, self.udp, role, self.mdns.mdns_interface) {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                log::error!("mdns: {err}");
+                std::thread::sleep(Duration::from_secs(2));
+                None
+            }
+        }
+    }
+    fn bind_udp(&self, role: Role) -> Option<UdpSocket> {
+        let address = self.addr(role)?;
+        let res = if address.ip().is_multicast() {
+            if self.listen {
+                self.multicast.receiver(address)
+            } else {
+                self.multicast.sender(address)
             }
-            Ok(sock)
-        }) {
+        } else {
+            std::net::UdpSocket::bind(if self.listen {
+                address
+            } else {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+            })
+            .and_then(|sock| {
+                if !self.listen {
+                    sock.connect(address)?;
+                }
+                Ok(sock)
+            })
+        };
+        match res {
             Ok(sock) => Some(sock),
             Err(err) => {
                 log::error!("udp bind: {err}");
@@ -89,8 +374,8 @@ impl Endpoint {
             }
         }
     }
-    fn bind(&self) -> Option<TcpListener> {
-        match std::net::TcpListener::bind(self.address) {
+    fn bind(&self, role: Role) -> Option<TcpListener> {
+        match std::net::TcpListener::bind(self.addr(role)?) {
             Ok(listener) => Some(listener),
             Err(err) => {
                 log::error!("bind: {err}");
@@ -99,8 +384,8 @@ impl Endpoint {
             }
         }
     }
-    fn connect(&self) -> Option<TcpStream> {
-        match std::net::TcpStream::connect(self.address) {
+    fn connect(&self, role: Role) -> Option<TcpStream> {
+        match std::net::TcpStream::connect(self.addr(role)?) {
             Ok(conn) => Some(conn),
             Err(err) => {
                 log::error!("connect: {err}");
@@ -111,67 +396,221 @@ impl Endpoint {
     }
 }
 
-impl ProdCons for Endpoint {
-    fn consume(&mut self, cons: &mut RingCons, inactivity_sec: u32) {
+impl Endpoint {
+    /// Receive any number of streams at once, and mix them together
+    fn mix(&mut self, mixer: &Mixer, opts: &Opts) {
         loop {
             if self.udp {
-                let Some(mut sock) = self.bind_udp() else {
+                let Some(sock) = self.bind_udp(Role::Record) else {
+                    continue;
+                };
+                mix_udp(&sock, mixer, opts);
+            } else {
+                let Some(listener) = self.bind(Role::Record) else {
+                    continue;
+                };
+                mix_tcp(&listener, mixer, opts);
+            }
+        }
+    }
+}
+
+impl ProdCons for Endpoint {
+    fn consume(&mut self, cons: &mut RingCons, opts: &Opts) -> Result<(), Rejected> {
+        let mut backoff = Backoff::default();
+        loop {
+            let res = if self.udp {
+                let Some(mut sock) = self.bind_udp(Role::Play) else {
                     continue;
                 };
-                sock.consume(cons, inactivity_sec);
+                sock.consume(cons, opts)
             } else if self.listen {
-                let Some(mut listener) = self.bind() else {
+                let Some(mut listener) = self.bind(Role::Play) else {
                     continue;
                 };
-                listener.consume(cons, inactivity_sec);
+                listener.consume(cons, opts)
             } else {
-                let Some(mut conn) = self.connect() else {
+                let Some(mut conn) = self.connect(Role::Play) else {
                     continue;
                 };
-                conn.consume(cons, inactivity_sec);
-            }
+                conn.consume(cons, opts)
+            };
+            backoff.ended(res);
         }
     }
-    fn produce(&mut self, prod: &mut RingProd, inactivity_sec: u32) {
+    fn produce(&mut self, prod: &mut RingProd, opts: &Opts) -> Result<(), Rejected> {
+        let mut backoff = Backoff::default();
         loop {
-            if self.udp {
-                let Some(mut sock) = self.bind_udp() else {
+            let res = if self.udp {
+                let Some(mut sock) = self.bind_udp(Role::Record) else {
                     continue;
                 };
-                sock.produce(prod, inactivity_sec);
+                sock.produce(prod, opts)
             } else if self.listen {
-                let Some(mut listener) = self.bind() else {
+                let Some(mut listener) = self.bind(Role::Record) else {
                     continue;
                 };
-                listener.produce(prod, inactivity_sec);
+                listener.produce(prod, opts)
             } else {
-                let Some(mut conn) = self.connect() else {
+                let Some(mut conn) = self.connect(Role::Record) else {
                     continue;
                 };
-                conn.produce(prod, inactivity_sec);
-            }
+                conn.produce(prod, opts)
+            };
+            backoff.ended(res);
         }
     }
 }
 
+// rejected clients don't concern the listener, it just goes on accepting others
 impl ProdCons for TcpListener {
-    fn produce(&mut self, prod: &mut RingProd, inactivity_sec: u32) {
-        while let Ok((mut conn, _addr)) = self.accept() {
-            conn.produce(prod, inactivity_sec);
+    fn produce(&mut self, prod: &mut RingProd, opts: &Opts) -> Result<(), Rejected> {
+        while let Ok((mut conn, addr)) = self.accept() {
+            if !opts.acl.allows(addr) {
+                continue;
+            }
+            let _ = conn.produce(prod, opts);
         }
+        Ok(())
     }
-    fn consume(&mut self, cons: &mut RingCons, inactivity_sec: u32) {
-        while let Ok((mut conn, _addr)) = self.accept() {
-            conn.consume(cons, inactivity_sec);
-        }
+    fn consume(&mut self, cons: &mut RingCons, opts: &Opts) -> Result<(), Rejected> {
+        let fanout = Fanout::new(opts.format);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| fanout.run(cons, &stop));
+            while let Ok((mut conn, addr)) = self.accept() {
+                if !opts.acl.allows(addr) {
+                    continue;
+                }
+                let Some(mut queue) = fanout.subscribe(addr, &conn) else {
+                    continue;
+                };
+                log::info!("client {addr} connected");
+                s.spawn(move || {
+                    let _ = conn.consume(&mut queue, opts);
+                    log::info!("client {addr} disconnected");
+                });
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+        Ok(())
     }
 }
 
 impl ProdCons for TcpStream {
-    fn consume(&mut self, cons: &mut RingCons, inactivity_sec: u32) {
-        let _ = self.set_read_timeout(Some(Duration::from_secs(inactivity_sec.into())));
+    fn consume(&mut self, cons: &mut RingCons, opts: &Opts) -> Result<(), Rejected> {
+        let _ = self.set_read_timeout(Some(Duration::from_secs(opts.inactivity_sec.into())));
+        let mut conn = match psk::Stream::new(self, opts.psk.as_ref()) {
+            Ok(x) => x,
+            Err(err) => {
+                log::error!("tcp handshake: {err}");
+                return Err(Rejected);
+            }
+        };
+        // the player doesn't answer, it just hangs up if it doesn't like our format
+        let start = Instant::now();
+        send_tcp(cons, opts, |data| conn.write_all(data));
+        if start.elapsed() < MIN_SESSION {
+            log::error!("tcp: peer hung up right away");
+            return Err(Rejected);
+        }
+        Ok(())
+    }
+    fn produce(&mut self, prod: &mut RingProd, opts: &Opts) -> Result<(), Rejected> {
+        let _ = self.set_read_timeout(Some(Duration::from_secs(opts.inactivity_sec.into())));
+        let mut conn = match psk::Stream::new(self, opts.psk.as_ref()) {
+            Ok(x) => x,
+            Err(err) => {
+                log::error!("tcp handshake: {err}");
+                return Err(Rejected);
+            }
+        };
+        let mut decoder = Decoder::Pcm;
+        if !opts.raw {
+            let mut header = [0u8; format::HEADER_LEN];
+            if let Err(err) = conn.read_exact(&mut header) {
+                log::error!("tcp handshake: {err}");
+                return Err(Rejected);
+            }
+            match opts
+                .format
+                .check_header(&header)
+                .map_err(|err| err.to_string())
+                .and_then(|codec| Decoder::new(opts.format, codec))
+            {
+                Ok(x) => decoder = x,
+                Err(err) => {
+                    log::error!("tcp handshake: {err}");
+                    return Err(Rejected);
+                }
+            }
+        }
         let mut buf = [0u8; 65536];
-        loop {
+        let mut jitter = Estimator::new(opts.jitter, opts.format);
+        match &mut decoder {
+            Decoder::Pcm => {
+                // a recorder that went away just returns no data, so stop there to reconnect
+                while let Ok(len @ 1..) = conn.read(&mut buf) {
+                    jitter.arrived(len);
+                    if !push_all(prod, &buf[..len]) {
+                        return Ok(());
+                    }
+                }
+            }
+            #[cfg(feature = "opus")]
+            Decoder::Opus(dec) => loop {
+                let mut len = [0u8; 2];
+                if conn.read_exact(&mut len).is_err() {
+                    return Ok(());
+                }
+                let len = usize::from(u16::from_le_bytes(len));
+                if conn.read_exact(&mut buf[..len]).is_err() {
+                    return Ok(());
+                }
+                match dec.decode(&buf[..len]) {
+                    Ok(pcm) => {
+                        jitter.arrived(pcm.len());
+                        if !push_all(prod, pcm) {
+                            return Ok(());
+                        }
+                    }
+                    Err(err) => {
+                        log::error!("opus: {err}");
+                        return Ok(());
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Send the stream over TCP, using `send` to write to the connection
+fn send_tcp(
+    cons: &mut RingCons,
+    opts: &Opts,
+    mut send: impl FnMut(&[u8]) -> std::io::Result<()> + Send,
+) {
+    if let Some(impair) = opts.impair {
+        let opts = Opts {
+            impair: None,
+            ..*opts
+        };
+        return impair.run(true, send, |send| send_tcp(cons, &opts, send));
+    }
+    let mut encoder = match Encoder::new(opts.format, &opts.codec) {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("encoder: {err}");
+            return;
+        }
+    };
+    if !opts.raw && send(&opts.format.header(opts.codec.codec)).is_err() {
+        return;
+    }
+    let mut buf = [0u8; 65536];
+    match &mut encoder {
+        Encoder::Pcm => loop {
             match cons.wait_occupied(1) {
                 Ok(()) => {}
                 Err(err) => match err {
@@ -180,124 +619,907 @@ impl ProdCons for TcpStream {
                 },
             }
             let len = cons.pop_slice(&mut buf);
-            if self.write_all(&buf[..len]).is_err() {
+            if send(&buf[..len]).is_err() {
                 break;
             }
-        }
-    }
-    fn produce(&mut self, prod: &mut RingProd, inactivity_sec: u32) {
-        let _ = self.set_read_timeout(Some(Duration::from_secs(inactivity_sec.into())));
-        let mut buf = [0u8; 65536];
-        while let Ok(len) = self.read(&mut buf) {
+        },
+        // packets are prefixed with their length
+        #[cfg(feature = "opus")]
+        Encoder::Opus(enc) => {
+            let mut pcm = vec![0u8; enc.frames() * opts.format.frame_bytes()];
             loop {
-                match prod.wait_vacant(len) {
-                    Ok(()) => break,
+                match cons.wait_occupied(pcm.len()) {
+                    Ok(()) => {}
                     Err(err) => match err {
-                        ringbuf_blocking::WaitError::Closed => return,
+                        ringbuf_blocking::WaitError::Closed => break,
                         ringbuf_blocking::WaitError::TimedOut => continue,
                     },
                 }
-            }
-            let mut pushed = 0;
-            while pushed < len {
-                pushed += prod.push_slice(&buf[pushed..len]);
+                cons.pop_slice(&mut pcm);
+                let len = match enc.encode(&pcm, &mut buf[2..][..codec::MAX_OPUS_PACKET]) {
+                    Ok(len) => len,
+                    Err(err) => {
+                        log::error!("opus: {err}");
+                        break;
+                    }
+                };
+                buf[..2].copy_from_slice(&(len as u16).to_le_bytes());
+                if send(&buf[..2 + len]).is_err() {
+                    break;
+                }
             }
         }
     }
 }
 
 impl ProdCons for UdpSocket {
-    fn consume(&mut self, cons: &mut RingCons, inactivity_sec: u32) {
-        let mut buf = [0u8; 65536];
+    fn consume(&mut self, cons: &mut RingCons, opts: &Opts) -> Result<(), Rejected> {
         if self
-            .set_write_timeout(Some(Duration::from_secs(inactivity_sec.into())))
+            .set_write_timeout(Some(Duration::from_secs(opts.inactivity_sec.into())))
             .is_err()
         {
+            return Ok(());
+        }
+        if self.peer_addr().is_ok() {
+            let sock = &*self;
+            let sealer = opts.psk.map(|key| psk::Sealer::new(&key));
+            send_udp(cons, opts, sealer.as_ref(), |data| {
+                sock.send(data).map(drop)
+            });
+            return Ok(());
+        }
+        // we're listening, so send to everyone who subscribed
+        let Ok(sock) = self.try_clone() else {
+            return Ok(());
+        };
+        let subscribers =
+            Subscribers::new(opts.format.header(opts.codec.codec), opts.psk, opts.acl);
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| subscribers.listen(&sock, &stop));
+            send_udp(cons, opts, subscribers.sealer(), |data| {
+                subscribers.send(self, data);
+                Ok(())
+            });
+            stop.store(true, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+    fn produce(&mut self, prod: &mut RingProd, opts: &Opts) -> Result<(), Rejected> {
+        if self.peer_addr().is_err() || opts.raw {
+            recv_udp(self, prod, opts);
+            return Ok(());
+        }
+        // we're connecting to a listening sender, so keep our subscription alive
+        let Ok(sock) = self.try_clone() else {
+            return Ok(());
+        };
+        let stop = AtomicBool::new(false);
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let sealer = opts.psk.map(|key| psk::Sealer::new(&key));
+                let mut buf = [0u8; 64];
+                while !stop.load(Ordering::Relaxed) {
+                    if let Ok(data) = psk::seal(sealer.as_ref(), &packet::SUBSCRIBE, &mut buf) {
+                        let _ = sock.send(data);
+                    }
+                    std::thread::sleep(ANNOUNCE_INTERVAL);
+                }
+            });
+            recv_udp(self, prod, opts);
+            stop.store(true, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+}
+
+/// Send the stream over UDP, using `send` to send each datagram
+fn send_udp(
+    cons: &mut RingCons,
+    opts: &Opts,
+    sealer: Option<&psk::Sealer>,
+    mut send: impl FnMut(&[u8]) -> std::io::Result<()> + Send,
+) {
+    if let Some(impair) = opts.impair {
+        let opts = Opts {
+            impair: None,
+            ..*opts
+        };
+        return impair.run(false, send, |send| send_udp(cons, &opts, sealer, send));
+    }
+    let mut sealed = [0u8; 65536];
+    let mut send = |data: &[u8]| send(psk::seal(sealer, data, &mut sealed)?);
+    let mut buf = [0u8; 65536];
+    let frame_bytes = opts.format.frame_bytes();
+    let max_payload = packet::max_payload(frame_bytes);
+    let mut encoder = match Encoder::new(opts.format, &opts.codec) {
+        Ok(x) => x,
+        Err(err) => {
+            log::error!("encoder: {err}");
             return;
         }
-        loop {
-            match cons.wait_occupied(1) {
-                Ok(()) => {}
-                Err(err) => match err {
-                    ringbuf_blocking::WaitError::Closed => break,
-                    ringbuf_blocking::WaitError::TimedOut => continue,
-                },
+    };
+    let chunk = match &encoder {
+        _ if opts.raw => 1,
+        Encoder::Pcm => frame_bytes,
+        #[cfg(feature = "opus")]
+        Encoder::Opus(enc) => enc.frames() * frame_bytes,
+    };
+    #[cfg(feature = "opus")]
+    let mut pcm = vec![0u8; chunk];
+    let mut seq = packet::Sequencer::default();
+    let mut rtp_sender = opts
+        .rtp
+        .then(|| rtp::Sender::new(opts.format, opts.codec.codec));
+    let mut write_header = |header: packet::Header, buf: &mut [u8]| match &mut rtp_sender {
+        Some(rtp) => rtp.write(header, buf),
+        None => header.write(buf),
+    };
+    let mut last_announce: Option<Instant> = None;
+    loop {
+        match cons.wait_occupied(chunk) {
+            Ok(()) => {}
+            Err(err) => match err {
+                ringbuf_blocking::WaitError::Closed => break,
+                ringbuf_blocking::WaitError::TimedOut => continue,
+            },
+        }
+        // the receiver may come up at any point, so keep announcing the format
+        if !opts.raw && !opts.rtp && last_announce.is_none_or(|x| x.elapsed() >= ANNOUNCE_INTERVAL)
+        {
+            if let Err(err) = send(&opts.format.header(opts.codec.codec)) {
+                log::error!("udp send: {err}");
+                break;
             }
-            let len = cons.pop_slice(&mut buf);
-            match self.send(&buf[..len]) {
-                Ok(_) => {}
-                Err(err) => {
-                    log::error!("udp send: {err}");
+            last_announce = Some(Instant::now());
+        }
+        let len = match &mut encoder {
+            _ if opts.raw => cons.pop_slice(&mut buf),
+            Encoder::Pcm => {
+                // only send whole frames, so a lost packet can't shift the channels
+                let len = cons.occupied_len().min(max_payload) / frame_bytes * frame_bytes;
+                let len = cons.pop_slice(&mut buf[packet::HEADER_LEN..][..len]);
+                if opts.rtp {
+                    rtp::swap_bytes(&mut buf[packet::HEADER_LEN..][..len], opts.format);
+                }
+                write_header(seq.next(len / frame_bytes), &mut buf);
+                packet::HEADER_LEN + len
+            }
+            #[cfg(feature = "opus")]
+            Encoder::Opus(enc) => {
+                cons.pop_slice(&mut pcm);
+                let out = &mut buf[packet::HEADER_LEN..][..codec::MAX_OPUS_PACKET];
+                let len = match enc.encode(&pcm, out) {
+                    Ok(len) => len,
+                    Err(err) => {
+                        log::error!("opus: {err}");
+                        break;
+                    }
+                };
+                write_header(seq.next(enc.frames()), &mut buf);
+                packet::HEADER_LEN + len
+            }
+        };
+        match send(&buf[..len]) {
+            Ok(()) => {}
+            Err(err) => {
+                log::error!("udp send: {err}");
+                break;
+            }
+        }
+    }
+}
+
+/// Receive a UDP stream
+fn recv_udp(sock: &UdpSocket, prod: &mut RingProd, opts: &Opts) {
+    let mut buf = [0u8; 65536];
+    let mut connected = false;
+    let mut stream = UdpStream::new(opts);
+    let mut opener = opts.psk.map(|key| psk::Opener::new(&key));
+    loop {
+        let res = if connected {
+            sock.recv(&mut buf).map(|len| (len, None))
+        } else {
+            sock.recv_from(&mut buf)
+                .map(|(len, other)| (len, Some(other)))
+        };
+        let (len, other) = match res {
+            Ok(x) => x,
+            // the sender isn't up yet, and our subscription bounced
+            Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(_) => break,
+        };
+        if other.is_some_and(|x| !opts.acl.allows(x)) {
+            continue;
+        }
+        let Some((session, data)) = psk::open(opener.as_mut(), &mut buf[..len]) else {
+            log::debug!("dropping unauthenticated or replayed {len} byte packet");
+            continue;
+        };
+        // only stick to senders of something we can play, so junk can't lock them out
+        let starts = other.is_some() && UdpStream::starts(opts, data);
+        if !stream.datagram(session, data, prod) {
+            return;
+        }
+        if !stream.announced || !starts {
+            continue;
+        }
+        if let Some(other) = other {
+            if opts.inactivity_sec != 0 {
+                if sock
+                    .set_read_timeout(Some(Duration::from_secs(opts.inactivity_sec.into())))
+                    .is_err()
+                {
                     break;
                 }
+                connected = sock.connect(other).is_ok();
             }
         }
     }
-    fn produce(&mut self, prod: &mut RingProd, inactivity_sec: u32) {
-        let mut buf = [0u8; 65536];
-        let mut connected = false;
-        while let Ok(len) = if connected {
-            self.recv(&mut buf)
+}
+
+/// Receive UDP streams from any number of senders, and mix them together
+fn mix_udp(sock: &UdpSocket, mixer: &Mixer, opts: &Opts) {
+    let timeout = match opts.inactivity_sec {
+        0 => MIX_IDLE_TIMEOUT,
+        secs => Duration::from_secs(secs.into()),
+    };
+    if sock
+        .set_read_timeout(Some(Duration::from_millis(500)))
+        .is_err()
+    {
+        return;
+    }
+    let mut buf = [0u8; 65536];
+    let mut sources = HashMap::<SocketAddr, (UdpStream, RingProd, Instant)>::new();
+    let mut opener = opts.psk.map(|key| psk::Opener::new(&key));
+    loop {
+        // senders that went quiet get dropped, their remaining audio still gets played
+        sources.retain(|addr, (_, _, seen)| {
+            let keep = seen.elapsed() < timeout;
+            if !keep {
+                log::info!("{addr} timed out");
+            }
+            keep
+        });
+        let (len, addr) = match sock.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => {
+                log::error!("udp recv: {err}");
+                return;
+            }
+        };
+        if !opts.acl.allows(addr) {
+            continue;
+        }
+        let Some((session, data)) = psk::open(opener.as_mut(), &mut buf[..len]) else {
+            log::debug!("dropping unauthenticated or replayed {len} byte packet from {addr}");
+            continue;
+        };
+        // so junk from random addresses can't pile up sources
+        if !sources.contains_key(&addr) && !UdpStream::starts(opts, data) {
+            log::debug!("dropping {len} byte packet from unknown sender {addr}");
+            continue;
+        }
+        let (stream, prod, seen) = sources
+            .entry(addr)
+            .or_insert_with(|| (UdpStream::new(opts), mixer.add(addr), Instant::now()));
+        *seen = Instant::now();
+        if !stream.datagram(session, data, prod) {
+            return;
+        }
+    }
+}
+
+/// Receive TCP streams from any number of senders, and mix them together
+fn mix_tcp(listener: &TcpListener, mixer: &Mixer, opts: &Opts) {
+    std::thread::scope(|s| {
+        while let Ok((mut conn, addr)) = listener.accept() {
+            if !opts.acl.allows(addr) {
+                continue;
+            }
+            let mut prod = mixer.add(addr);
+            s.spawn(move || {
+                let _ = conn.produce(&mut prod, opts);
+            });
+        }
+    });
+}
+
+/// State of a single incoming UDP stream
+struct UdpStream {
+    opts: Opts,
+    reorderer: packet::Reorderer,
+    concealer: Concealer,
+    missing: [u8; 4096],
+    decoder: Decoder,
+    jitter: Estimator,
+    /// Whether the sender announced a format we can play
+    announced: bool,
+    rtp: Option<rtp::Receiver>,
+    /// PSK session of the sender we're playing, and when it last sent something
+    session: Option<(u64, Instant)>,
+}
+
+impl UdpStream {
+    fn new(opts: &Opts) -> Self {
+        Self {
+            opts: *opts,
+            reorderer: packet::Reorderer::new(opts.format.rate, opts.psk.is_some()),
+            concealer: Concealer::new(opts.conceal, opts.format),
+            missing: [0u8; 4096],
+            // RTP streams don't announce their codec, it's set on the command line instead
+            decoder: match Decoder::new(opts.format, opts.codec.codec) {
+                Ok(x) if opts.rtp => x,
+                Ok(_) => Decoder::Pcm,
+                Err(err) => {
+                    log::error!("rtp: {err}");
+                    Decoder::Pcm
+                }
+            },
+            jitter: Estimator::new(opts.jitter, opts.format),
+            // raw and RTP streams don't announce their format, so there's nothing to wait for
+            announced: opts.raw || opts.rtp,
+            rtp: opts
+                .rtp
+                .then(|| rtp::Receiver::new(opts.format, opts.codec.codec)),
+            session: None,
+        }
+    }
+    /// Whether a datagram from a new sender is the start of a stream we can play
+    fn starts(opts: &Opts, data: &[u8]) -> bool {
+        if opts.raw {
+            // nothing to check
+            !data.is_empty()
+        } else if opts.rtp {
+            rtp::is_packet(data)
         } else {
-            self.recv_from(&mut buf).and_then(|(len, other)| {
-                if inactivity_sec != 0 {
-                    self.set_read_timeout(Some(Duration::from_secs(inactivity_sec.into())))?;
-                    connected = self.connect(other).is_ok();
+            // senders announce their format before anything else
+            opts.format.check_header(data).is_ok()
+        }
+    }
+    /// Handle a received (and opened) datagram, returning false if the ring buffer was closed
+    fn datagram(&mut self, session: Option<u64>, data: &mut [u8], prod: &mut RingProd) -> bool {
+        let opts = &self.opts;
+        if let Some(session) = session {
+            match self.session {
+                Some((cur, ref mut seen)) if cur == session => *seen = Instant::now(),
+                // a restarted sender comes back with a new session, but so does a replay of one
+                // we don't remember, so only switch once the current one went quiet
+                Some((_, seen)) if seen.elapsed() < SESSION_TIMEOUT => {
+                    log::debug!("dropping packet from another session");
+                    return true;
                 }
-                Ok(len)
-            })
-        } {
-            loop {
-                match prod.wait_vacant(len) {
-                    Ok(()) => break,
-                    Err(err) => match err {
-                        ringbuf_blocking::WaitError::Closed => return,
-                        ringbuf_blocking::WaitError::TimedOut => continue,
-                    },
+                cur => {
+                    if cur.is_some() {
+                        log::info!("udp sender restarted, resyncing");
+                        self.reorderer = packet::Reorderer::new(opts.format.rate, true);
+                    }
+                    self.session = Some((session, Instant::now()));
+                }
+            }
+        }
+        let frame_bytes = opts.format.frame_bytes();
+        if !opts.raw && !opts.rtp && StreamFormat::is_header(data) {
+            match opts.format.check_header(data) {
+                Ok(codec) if self.announced && self.decoder.codec() == codec => {}
+                Ok(codec) => match Decoder::new(opts.format, codec) {
+                    Ok(x) => {
+                        self.decoder = x;
+                        self.announced = true;
+                    }
+                    Err(err) => {
+                        log::error!("udp announce: {err}");
+                        self.announced = false;
+                    }
+                },
+                Err(err) => {
+                    log::error!("udp announce: {err}");
+                    self.announced = false;
+                }
+            }
+            return true;
+        }
+        if !self.announced {
+            return true;
+        }
+        if opts.raw {
+            self.jitter.arrived(data.len());
+            return push_all(prod, data);
+        }
+        let (header, payload) = match &mut self.rtp {
+            Some(rtp) => {
+                let Some(packet) = rtp.parse(data) else {
+                    log::debug!("dropping invalid {} byte rtp packet", data.len());
+                    return true;
+                };
+                if packet.new_source {
+                    self.reorderer = packet::Reorderer::new(opts.format.rate, opts.psk.is_some());
+                }
+                (packet.header, &mut data[packet.payload])
+            }
+            None => {
+                let Some(header) = packet::Header::parse(data) else {
+                    log::debug!("dropping unknown {} byte packet", data.len());
+                    return true;
+                };
+                (header, &mut data[packet::HEADER_LEN..])
+            }
+        };
+        let frames = match &self.decoder {
+            Decoder::Pcm => {
+                if !payload.len().is_multiple_of(frame_bytes) {
+                    log::debug!("dropping packet with a partial frame");
+                    return true;
                 }
+                payload.len() / frame_bytes
             }
-            let mut pushed = 0;
-            while pushed < len {
-                pushed += prod.push_slice(&buf[pushed..len]);
+            #[cfg(feature = "opus")]
+            Decoder::Opus(dec) => match dec.packet_frames(payload) {
+                Ok(x) => x,
+                Err(err) => {
+                    log::debug!("dropping invalid opus packet: {err}");
+                    return true;
+                }
+            },
+        };
+        let packet::Verdict::Accept { gap } = self.reorderer.packet(header, frames) else {
+            return true;
+        };
+        // the gap gets filled in, so it counts as having arrived too
+        self.jitter.arrived((gap as usize + frames) * frame_bytes);
+        match &mut self.decoder {
+            Decoder::Pcm => {
+                if opts.rtp {
+                    rtp::swap_bytes(payload, opts.format);
+                }
+                let mut gap = gap as usize * frame_bytes;
+                while gap > 0 {
+                    let len = gap.min(self.missing.len() / frame_bytes * frame_bytes);
+                    self.concealer.conceal(&mut self.missing[..len]);
+                    if !push_all(prod, &self.missing[..len]) {
+                        return false;
+                    }
+                    gap -= len;
+                }
+                self.concealer.played(payload);
+                push_all(prod, payload)
+            }
+            #[cfg(feature = "opus")]
+            Decoder::Opus(dec) => match push_opus(prod, dec, payload, gap as usize) {
+                Ok(x) => x,
+                Err(err) => {
+                    log::error!("opus: {err}");
+                    true
+                }
+            },
+        }
+    }
+}
+
+/// Decode an opus packet into the ring buffer, first filling the gap preceding it (in frames)
+///
+/// As much of the gap as possible is recovered using the packet's FEC data, and the rest is
+/// concealed by opus's PLC. Returns false if the ring buffer was closed
+#[cfg(feature = "opus")]
+fn push_opus(
+    prod: &mut RingProd,
+    dec: &mut codec::OpusDecoder,
+    packet: &[u8],
+    mut gap: usize,
+) -> Result<bool, opus::Error> {
+    let frame_bytes = dec.frame_bytes();
+    while gap > dec.frames() {
+        let pcm = dec.conceal(gap - dec.frames())?;
+        if pcm.is_empty() {
+            break;
+        }
+        gap -= pcm.len() / frame_bytes;
+        if !push_all(prod, pcm) {
+            return Ok(false);
+        }
+    }
+    if gap > 0 && !push_all(prod, dec.recover(packet, gap)?) {
+        return Ok(false);
+    }
+    Ok(push_all(prod, dec.decode(packet)?))
+}
+
+/// Push all of the data into the ring buffer, waiting for it to have enough space
+///
+/// Returns false if the ring buffer was closed
+fn push_all(prod: &mut RingProd, data: &[u8]) -> bool {
+    for chunk in data.chunks(65536) {
+        loop {
+            match prod.wait_vacant(chunk.len()) {
+                Ok(()) => break,
+                Err(err) => match err {
+                    ringbuf_blocking::WaitError::Closed => return false,
+                    ringbuf_blocking::WaitError::TimedOut => continue,
+                },
             }
         }
+        let mut pushed = 0;
+        while pushed < chunk.len() {
+            pushed += prod.push_slice(&chunk[pushed..]);
+        }
     }
+    true
 }
 
 fn main() {
     env_logger::init();
-    let mut args = Cli::parse();
+    let args = Cli::parse();
+    let command = match args.command {
+        Command::Discover { wait_sec } => {
+            let wait = Duration::from_secs(wait_sec.into());
+            if let Err(err) = mdns::discover(args.net.mdns.mdns_interface, wait) {
+                log::error!("discover: {err}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::Stream(command) => command,
+    };
+    let Some(address) = args.net.address.clone() else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "play and record need an --address",
+            )
+            .exit()
+    };
+    // the jitter buffer target outlives restarts of the main loop
+    let jitter: &'static jitter::Target = Box::leak(Box::new(jitter::Target::new(
+        match command {
+            Cmd::Play { format, .. } | Cmd::Record { format, .. } => format,
+        },
+        match command {
+            Cmd::Play { late_pct, .. } => late_pct,
+            Cmd::Record { .. } => 0.0,
+        },
+    )));
+    let opts = Opts {
+        inactivity_sec: args.inactivity_sec.unwrap_or(2),
+        format: match command {
+            Cmd::Play { format, .. } | Cmd::Record { format, .. } => format,
+        },
+        raw: args.net.raw,
+        rtp: args.net.rtp,
+        conceal: match command {
+            Cmd::Play { conceal, .. } => conceal,
+            Cmd::Record { .. } => Concealment::Silence,
+        },
+        codec: match command {
+            Cmd::Play { codec, .. } => codec.unwrap_or(codec::Codec::Pcm).into(),
+            Cmd::Record { codec, .. } => codec,
+        },
+        // the mixer has its own buffers for every source
+        jitter: match command {
+            Cmd::Play { mix: false, .. } => Some(jitter),
+            Cmd::Play { .. } | Cmd::Record { .. } => None,
+        },
+        impair: args.impair,
+        psk: args.psk_file.as_deref().map(|path| {
+            psk::Key::load(path).unwrap_or_else(|err| {
+                Cli::command()
+                    .error(ErrorKind::InvalidValue, format!("--psk-file: {err}"))
+                    .exit()
+            })
+        }),
+        acl: Box::leak(Box::new(Acl::new(args.allow.clone(), args.deny.clone()))),
+    };
+    if let Err(err) = Encoder::new(opts.format, &opts.codec) {
+        Cli::command().error(ErrorKind::InvalidValue, err).exit();
+    }
+    if opts.raw && opts.codec.codec != codec::Codec::Pcm {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--raw streams can only be sent as pcm",
+            )
+            .exit();
+    }
+    if matches!(command, Cmd::Play { codec: Some(_), .. }) && !opts.rtp {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "play only needs --codec for --rtp streams, others announce their codec",
+            )
+            .exit();
+    }
+    if opts.rtp {
+        if let Err(err) = rtp::check(opts.format, opts.codec.codec) {
+            Cli::command().error(ErrorKind::InvalidValue, err).exit();
+        }
+        if args.net.listen == matches!(command, Cmd::Record { .. }) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--rtp streams can only be sent by a connecting recorder to a listening player",
+                )
+                .exit();
+        }
+    }
+    let addr = match address {
+        Address::Ip(addr) => addr,
+        Address::Mdns(_) => {
+            if args.net.listen {
+                Cli::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        "listening needs an IP address to bind (pass --name to pick the mDNS one)",
+                    )
+                    .exit();
+            }
+            if args.sdp.is_some() {
+                Cli::command()
+                    .error(ErrorKind::ArgumentConflict, "--sdp needs an IP address")
+                    .exit();
+            }
+            // mDNS names never stand for multicast groups
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)
+        }
+    };
+    if let Err(err) = args.net.multicast.check(addr.ip()) {
+        Cli::command().error(ErrorKind::InvalidValue, err).exit();
+    }
+    if addr.ip().is_multicast() {
+        if !args.net.udp {
+            Cli::command()
+                .error(ErrorKind::ArgumentConflict, "multicast needs --udp")
+                .exit();
+        }
+        if args.net.listen == matches!(command, Cmd::Record { .. }) {
+            Cli::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "multicast streams can only be sent by a connecting recorder to listening players",
+                )
+                .exit();
+        }
+    }
+    if let Some(path) = &args.sdp {
+        let sdp = rtp::sdp(opts.format, opts.codec.codec, addr);
+        if let Err(err) = std::fs::write(path, sdp) {
+            Cli::command()
+                .error(ErrorKind::Io, format!("--sdp: {err}"))
+                .exit();
+        }
+    }
+    #[cfg(feature = "pipewire")]
+    if let Cmd::Record {
+        backend: RecordBackend::Pipewire,
+        node_name: None,
+        ..
+    } = command
+    {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the pipewire backend needs a --node-name to record from",
+            )
+            .exit();
+    }
+    #[cfg(feature = "pipewire")]
+    let can_create_sink = matches!(
+        command,
+        Cmd::Record {
+            backend: RecordBackend::Pipewire,
+            ..
+        }
+    );
+    #[cfg(not(feature = "pipewire"))]
+    let can_create_sink = false;
+    if matches!(
+        command,
+        Cmd::Record {
+            virtual_sink: true,
+            ..
+        }
+    ) && !can_create_sink
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--virtual-sink needs --backend pipewire",
+            )
+            .exit();
+    }
+    #[cfg(feature = "pipewire")]
+    if matches!(command, Cmd::Play { backend, as_source: Some(_), .. } if backend != PlayBackend::Pipewire)
+    {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--as-source needs --backend pipewire",
+            )
+            .exit();
+    }
+    if matches!(command, Cmd::Play { mix: true, .. }) && !args.net.listen {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--mix only works when listening",
+            )
+            .exit();
+    }
+    // the player only sends subscriptions, so there's nothing for it to impair
+    if matches!(command, Cmd::Play { .. }) && args.impair.is_some() {
+        Cli::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--impair only affects what we send, so it only works when recording",
+            )
+            .exit();
+    }
+    let name = args.net.mdns.name.clone().unwrap_or_else(mdns::hostname);
+    if name.is_empty() || name.len() > mdns::MAX_NAME {
+        Cli::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("--name must be 1 to {} bytes long", mdns::MAX_NAME),
+            )
+            .exit();
+    }
+    // let connecting peers find us by name
+    if args.net.listen && !args.net.mdns.no_mdns && !addr.ip().is_multicast() {
+        let ip = match addr.ip() {
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            ip => ip.to_canonical(),
+        };
+        if ip.is_ipv4() {
+            let peer = mdns::Peer::new(
+                name,
+                args.net.udp,
+                SocketAddr::new(ip, addr.port()),
+                match command {
+                    Cmd::Play { .. } => Role::Play,
+                    Cmd::Record { .. } => Role::Record,
+                },
+                opts.format,
+                match command {
+                    Cmd::Play { .. } => None,
+                    Cmd::Record { codec, .. } => Some(codec.codec),
+                },
+            );
+            let interface = args.net.mdns.mdns_interface;
+            std::thread::spawn(move || loop {
+                if let Err(err) = mdns::advertise(&peer, interface) {
+                    log::error!("mdns: {err}");
+                }
+                std::thread::sleep(Duration::from_secs(2));
+            });
+        } else {
+            log::info!("not advertising {addr} over mDNS, which only supports IPv4");
+        }
+    }
     loop {
         let buf = BlockingRb::new(0x40000);
         let (mut prod, mut cons) = buf.split();
-        let res = match args.command.clone() {
-            Cmd::Record { node_name } => {
-                std::thread::spawn(move || {
-                    args.net
-                        .consume(&mut cons, args.inactivity_sec.unwrap_or(2))
-                });
-                record::main(node_name, prod)
+        let res = match command.clone() {
+            Cmd::Record {
+                backend,
+                node_name,
+                virtual_sink,
+                device,
+                #[cfg(feature = "jack")]
+                jack,
+                file,
+                ..
+            } => {
+                let mut net = args.net.clone();
+                std::thread::spawn(move || net.consume(&mut cons, &opts));
+                #[cfg(not(feature = "pipewire"))]
+                let _ = (node_name, virtual_sink);
+                match backend {
+                    #[cfg(feature = "pipewire")]
+                    RecordBackend::Pipewire => record::main(
+                        node_name.unwrap_or_default(),
+                        virtual_sink,
+                        opts.format,
+                        prod,
+                    ),
+                    #[cfg(feature = "alsa")]
+                    RecordBackend::Alsa => record_alsa::main(device, opts.format, prod),
+                    #[cfg(feature = "pulse")]
+                    RecordBackend::Pulse => record_pulse::main(device, opts.format, prod),
+                    #[cfg(feature = "jack")]
+                    RecordBackend::Jack => record_jack::main(jack, opts.format, prod),
+                    RecordBackend::Cpal => {
+                        let device = Some(device).filter(|x| x != "default");
+                        record_cpal::main(device, opts.format, prod)
+                    }
+                    RecordBackend::File | RecordBackend::Stdin => {
+                        let file = file.filter(|_| backend == RecordBackend::File);
+                        match record_file::main(file, opts.format, prod) {
+                            // the input ended, so there's nothing left to restart
+                            Ok(()) => return,
+                            Err(err) => Err(err),
+                        }
+                    }
+                }
             }
             Cmd::Play {
+                backend,
                 buffer_samples,
                 device_name,
+                #[cfg(feature = "pipewire")]
+                as_source,
+                #[cfg(feature = "jack")]
+                jack,
+                file,
+                conceal,
+                mix,
+                gain,
+                max_drift_ppm,
+                ..
             } => {
-                std::thread::spawn(move || {
-                    args.net
-                        .produce(&mut prod, args.inactivity_sec.unwrap_or(2))
-                });
-                play::main(
-                    cons,
-                    {
-                        let buf = buffer_samples.map(|x| x * 2);
-                        if args.net.udp {
-                            Some(buf.unwrap_or(1000000))
-                        } else {
-                            buf
-                        }
-                    },
-                    device_name,
-                )
+                let buffer_frames = buffer_samples.map(|x| x / usize::from(opts.format.channels));
+                let mut net = args.net.clone();
+                if mix {
+                    std::thread::spawn(move || {
+                        let mixer = Mixer::new(opts.format, gain, buffer_frames);
+                        std::thread::scope(|s| {
+                            s.spawn(|| mixer.run(&mut prod));
+                            net.mix(&mixer, &opts);
+                        });
+                    });
+                } else {
+                    std::thread::spawn(move || net.produce(&mut prod, &opts));
+                }
+                match backend {
+                    PlayBackend::Cpal => play::main(
+                        cons,
+                        opts.format,
+                        jitter,
+                        buffer_frames,
+                        max_drift_ppm,
+                        device_name,
+                        conceal,
+                    ),
+                    #[cfg(feature = "pipewire")]
+                    PlayBackend::Pipewire => play_pipewire::main(
+                        cons,
+                        opts.format,
+                        jitter,
+                        buffer_frames,
+                        max_drift_ppm,
+                        device_name,
+                        as_source,
+                        conceal,
+                    ),
+                    #[cfg(feature = "jack")]
+                    PlayBackend::Jack => play_jack::main(
+                        cons,
+                        opts.format,
+                        jitter,
+                        buffer_frames,
+                        max_drift_ppm,
+                        jack,
+                        conceal,
+                    ),
+                    PlayBackend::File | PlayBackend::Stdout => play_file::main(
+                        cons,
+                        opts.format,
+                        jitter,
+                        buffer_frames,
+                        max_drift_ppm,
+                        file.filter(|_| backend == PlayBackend::File),
+                        conceal,
+                    ),
+                }
             }
         };
         match res {
@@ -0,0 +1,737 @@
+//! mDNS/DNS-SD, so peers can be found by name instead of by address
+//!
+//! Listening instances advertise themselves as `<name>._ihatelatency._udp.local` (or `_tcp`),
+//! with their role and stream format in a TXT record and their address under
+//! `<name>-ihatelatency.local` (the machine's own name belongs to the system's mDNS responder),
+//! and connecting ones look their peer up again before every connection attempt, so a peer that
+//! got a new address is still found. Only what that needs is implemented: IPv4 only, no probing
+//! for name conflicts, no known-answer suppression, and no name compression in what we send.
+
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use clap::{Args, ValueEnum};
+use socket2::{Domain, Socket, Type};
+
+use crate::{codec::Codec, format::StreamFormat};
+
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+const SERVICE: &str = "_ihatelatency";
+const DOMAIN: &str = "local";
+/// What DNS-SD browsers query to list all service types
+const SERVICE_TYPES: [&str; 4] = ["_services", "_dns-sd", "_udp", DOMAIN];
+const TTL: u32 = 120;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set in the class of records that replace cached ones instead of adding to them
+const CACHE_FLUSH: u16 = 0x8000;
+/// Longest label a name may have
+const MAX_LABEL: usize = 63;
+/// Appended to the instance name to get the host name we advertise our address under, so we
+/// don't fight the system's own mDNS responder over the machine's name
+const HOST_SUFFIX: &str = "-ihatelatency";
+/// Longest name an instance may have
+pub const MAX_NAME: usize = MAX_LABEL - HOST_SUFFIX.len();
+/// How often to repeat queries that went unanswered
+const QUERY_INTERVAL: Duration = Duration::from_secs(1);
+/// How long to look for a peer before giving up on a connection attempt
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Args, Clone, Debug)]
+pub struct MdnsOpts {
+    /// Name to advertise a listening instance as over mDNS (defaults to the host name)
+    #[arg(long, requires = "listen")]
+    pub name: Option<String>,
+    /// Don't advertise a listening instance over mDNS
+    #[arg(long, conflicts_with = "name")]
+    pub no_mdns: bool,
+    /// Interface to use for mDNS, as its IPv4 address
+    #[arg(long, value_name = "ADDR")]
+    pub mdns_interface: Option<Ipv4Addr>,
+}
+
+/// Where to connect to (or listen at)
+#[derive(Clone, Debug)]
+pub enum Address {
+    Ip(SocketAddr),
+    /// Instance advertised over mDNS
+    Mdns(String),
+}
+
+impl FromStr for Address {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("mdns:") {
+            Some("") => Err("missing the name after mdns:".into()),
+            Some(name) => Ok(Self::Mdns(name.into())),
+            None => s.parse().map(Self::Ip).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+/// What a listening instance does with the streams of its peers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    Play,
+    Record,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Play => "play",
+            Self::Record => "record",
+        })
+    }
+}
+
+/// A listening instance, as advertised
+#[derive(Clone, Debug)]
+pub struct Peer {
+    pub name: String,
+    pub udp: bool,
+    pub addr: SocketAddr,
+    /// Contents of the TXT record, as key=value pairs
+    pub txt: Vec<String>,
+}
+
+impl Peer {
+    pub fn new(
+        name: String,
+        udp: bool,
+        addr: SocketAddr,
+        role: Role,
+        format: StreamFormat,
+        codec: Option<Codec>,
+    ) -> Self {
+        let mut txt = vec![
+            format!("role={role}"),
+            format!("format={}", format.sample_format),
+            format!("rate={}", format.rate),
+            format!("channels={}", format.channels),
+        ];
+        if let Some(codec) = codec.and_then(|x| x.to_possible_value()) {
+            txt.push(format!("codec={}", codec.get_name()));
+        }
+        Self {
+            name,
+            udp,
+            addr,
+            txt,
+        }
+    }
+    /// Value of a TXT record key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.txt
+            .iter()
+            .find_map(|x| x.strip_prefix(key)?.strip_prefix('='))
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let proto = if self.udp { "udp" } else { "tcp" };
+        let role = self.get("role").unwrap_or("?");
+        write!(f, "{}: {proto} {role} at {}", self.name, self.addr)?;
+        if let (Some(format), Some(rate), Some(channels)) =
+            (self.get("format"), self.get("rate"), self.get("channels"))
+        {
+            write!(f, ", {format} {rate}Hz {channels}ch")?;
+        }
+        if let Some(codec) = self.get("codec") {
+            write!(f, ", {codec}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of this machine, which listening instances are advertised as by default
+pub fn hostname() -> String {
+    let name = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    match name.trim().split('.').next() {
+        Some(name) if !name.is_empty() => name.into(),
+        _ => "ihatelatency".into(),
+    }
+}
+
+fn proto(udp: bool) -> &'static str {
+    if udp {
+        "_udp"
+    } else {
+        "_tcp"
+    }
+}
+
+/// Socket on the mDNS port that joined its group on `interface`
+fn socket(interface: Option<Ipv4Addr>) -> io::Result<UdpSocket> {
+    let sock = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    // other responders (like avahi) usually have the port open as well
+    sock.set_reuse_address(true)?;
+    sock.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    let interface = interface.unwrap_or(Ipv4Addr::UNSPECIFIED);
+    sock.join_multicast_v4(&GROUP, &interface)?;
+    sock.set_multicast_if_v4(&interface)?;
+    sock.set_multicast_ttl_v4(255)?;
+    Ok(sock.into())
+}
+
+/// Whether two names are the same (which DNS compares case-insensitively)
+fn same(name: &[String], other: &[&str]) -> bool {
+    name.len() == other.len()
+        && name
+            .iter()
+            .zip(other)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+/// Key to look a name up by
+fn key(name: &[String]) -> String {
+    name.join(".").to_ascii_lowercase()
+}
+
+/// DNS message being built
+struct Message(Vec<u8>);
+
+impl Message {
+    fn new(response: bool, questions: u16, answers: u16) -> Self {
+        let mut buf = vec![0u8; 12];
+        // mDNS ignores the ID, and only uses the authoritative answer flag
+        if response {
+            buf[2] = 0x84;
+        }
+        buf[4..6].copy_from_slice(&questions.to_be_bytes());
+        buf[6..8].copy_from_slice(&answers.to_be_bytes());
+        Self(buf)
+    }
+    fn name(&mut self, name: &[&str]) {
+        for label in name {
+            let label = &label.as_bytes()[..label.len().min(MAX_LABEL)];
+            self.0.push(label.len() as u8);
+            self.0.extend_from_slice(label);
+        }
+        self.0.push(0);
+    }
+    fn question(&mut self, name: &[&str], qtype: u16) {
+        self.name(name);
+        self.0.extend_from_slice(&qtype.to_be_bytes());
+        self.0.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    /// Add a record whose data gets written by `data`
+    fn record(&mut self, name: &[&str], rtype: u16, unique: bool, data: impl FnOnce(&mut Self)) {
+        self.name(name);
+        let class = if unique {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        self.0.extend_from_slice(&rtype.to_be_bytes());
+        self.0.extend_from_slice(&class.to_be_bytes());
+        self.0.extend_from_slice(&TTL.to_be_bytes());
+        let start = self.0.len();
+        self.0.extend_from_slice(&[0, 0]);
+        data(self);
+        let len = (self.0.len() - start - 2) as u16;
+        self.0[start..start + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Data of a received record
+enum Data {
+    Ptr(Vec<String>),
+    Srv { port: u16, target: Vec<String> },
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    Other,
+}
+
+/// Received DNS message
+struct Parsed {
+    response: bool,
+    questions: Vec<(Vec<String>, u16)>,
+    /// Answer, authority and additional records, which mDNS treats the same
+    records: Vec<(Vec<String>, Data)>,
+}
+
+/// Read a (possibly compressed) name at `pos`, returning its labels and where it ends
+fn read_name(msg: &[u8], mut pos: usize) -> Option<(Vec<String>, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // a name can't have more labels than this, so more means a pointer loop
+    for _ in 0..128 {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some((labels, end.unwrap_or(pos + 1))),
+            1..=63 => {
+                let label = msg.get(pos + 1..pos + 1 + usize::from(len))?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                pos += 1 + usize::from(len);
+            }
+            0xc0.. => {
+                let ptr = u16::from_be_bytes([len, *msg.get(pos + 1)?]) & 0x3fff;
+                end.get_or_insert(pos + 2);
+                pos = ptr.into();
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(msg.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn parse(msg: &[u8]) -> Option<Parsed> {
+    let flags = read_u16(msg, 2)?;
+    let questions = read_u16(msg, 4)?;
+    let records = (6..12)
+        .step_by(2)
+        .map(|x| read_u16(msg, x).map(usize::from))
+        .sum::<Option<usize>>()?;
+    let mut ret = Parsed {
+        response: flags & 0x8000 != 0,
+        questions: Vec::new(),
+        records: Vec::new(),
+    };
+    let mut pos = 12;
+    for _ in 0..questions {
+        let (name, end) = read_name(msg, pos)?;
+        ret.questions.push((name, read_u16(msg, end)?));
+        pos = end + 4;
+    }
+    for _ in 0..records {
+        let (name, end) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, end)?;
+        let start = end + 10;
+        let len = usize::from(read_u16(msg, end + 8)?);
+        let data = msg.get(start..start + len)?;
+        let data = match rtype {
+            TYPE_PTR => Data::Ptr(read_name(msg, start)?.0),
+            TYPE_SRV => Data::Srv {
+                port: read_u16(data, 4)?,
+                target: read_name(msg, start + 6)?.0,
+            },
+            TYPE_TXT => {
+                let mut strings = Vec::new();
+                let mut rest = data;
+                while let Some((&len, tail)) = rest.split_first() {
+                    let string = tail.get(..usize::from(len))?;
+                    strings.push(String::from_utf8_lossy(string).into_owned());
+                    rest = &tail[usize::from(len)..];
+                }
+                Data::Txt(strings)
+            }
+            TYPE_A => Data::A(<[u8; 4]>::try_from(data).ok()?.into()),
+            _ => Data::Other,
+        };
+        ret.records.push((name, data));
+        pos = start + len;
+    }
+    Some(ret)
+}
+
+/// Address the advertised one stands for, or the one we send to the group from
+fn local_ip(ip: Ipv4Addr, interface: Option<Ipv4Addr>) -> io::Result<Ipv4Addr> {
+    if !ip.is_unspecified() {
+        return Ok(ip);
+    }
+    if let Some(interface) = interface {
+        return Ok(interface);
+    }
+    let sock = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    sock.connect((GROUP, PORT))?;
+    match sock.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(io::Error::other("no IPv4 address to advertise")),
+    }
+}
+
+/// Records advertising `peer` at `ip`, also listing our service type if `types`
+fn response(peer: &Peer, ip: Ipv4Addr, types: bool) -> Vec<u8> {
+    let host = format!("{}{HOST_SUFFIX}", peer.name);
+    let proto = proto(peer.udp);
+    let service = [SERVICE, proto, DOMAIN];
+    let instance = [&*peer.name, SERVICE, proto, DOMAIN];
+    let target = [&*host, DOMAIN];
+    let mut msg = Message::new(true, 0, 4 + u16::from(types));
+    if types {
+        msg.record(&SERVICE_TYPES, TYPE_PTR, false, |msg| msg.name(&service));
+    }
+    msg.record(&service, TYPE_PTR, false, |msg| msg.name(&instance));
+    msg.record(&instance, TYPE_SRV, true, |msg| {
+        // no priority or weight
+        msg.0.extend_from_slice(&[0; 4]);
+        msg.0.extend_from_slice(&peer.addr.port().to_be_bytes());
+        msg.name(&target);
+    });
+    msg.record(&instance, TYPE_TXT, true, |msg| {
+        for string in &peer.txt {
+            let string = &string.as_bytes()[..string.len().min(255)];
+            msg.0.push(string.len() as u8);
+            msg.0.extend_from_slice(string);
+        }
+    });
+    msg.record(&target, TYPE_A, true, |msg| {
+        msg.0.extend_from_slice(&ip.octets())
+    });
+    msg.0
+}
+
+/// Advertise `peer` (which is us), answering queries for it until an error happens
+pub fn advertise(peer: &Peer, interface: Option<Ipv4Addr>) -> io::Result<()> {
+    let IpAddr::V4(ip) = peer.addr.ip() else {
+        return Err(io::Error::other("mDNS only advertises IPv4 addresses"));
+    };
+    let sock = socket(interface)?;
+    let host = format!("{}{HOST_SUFFIX}", peer.name);
+    let proto = proto(peer.udp);
+    let service = [SERVICE, proto, DOMAIN];
+    let instance = [&*peer.name, SERVICE, proto, DOMAIN];
+    let target = [&*host, DOMAIN];
+    let response = |types| Ok::<_, io::Error>(response(peer, local_ip(ip, interface)?, types));
+    // announce ourselves, so browsers that are already running notice us
+    sock.send_to(&response(false)?, (GROUP, PORT))?;
+    log::info!("advertising {}.{}.{}", peer.name, SERVICE, proto);
+    let mut buf = [0u8; 9000];
+    loop {
+        let (len, _) = sock.recv_from(&mut buf)?;
+        let Some(msg) = parse(&buf[..len]) else {
+            continue;
+        };
+        if msg.response {
+            continue;
+        }
+        let asked = |name: &[&str], types: &[u16]| {
+            msg.questions.iter().any(|(x, qtype)| {
+                same(x, name) && [TYPE_ANY].iter().chain(types).any(|x| x == qtype)
+            })
+        };
+        let types = asked(&SERVICE_TYPES, &[TYPE_PTR]);
+        if types
+            || asked(&service, &[TYPE_PTR])
+            || asked(&instance, &[TYPE_SRV, TYPE_TXT])
+            || asked(&target, &[TYPE_A])
+        {
+            sock.send_to(&response(types)?, (GROUP, PORT))?;
+        }
+    }
+}
+
+/// Look for listening instances (using UDP, TCP, or either), until `done` is happy with the
+/// ones found so far or `timeout` passes
+pub fn browse(
+    udp: Option<bool>,
+    interface: Option<Ipv4Addr>,
+    timeout: Duration,
+    mut done: impl FnMut(&[Peer]) -> bool,
+) -> io::Result<Vec<Peer>> {
+    let sock = socket(interface)?;
+    let protos: Vec<bool> = udp.map_or(vec![true, false], |x| vec![x]);
+    let deadline = Instant::now() + timeout;
+    let mut last_query: Option<Instant> = None;
+    // what we heard so far, by the names the records are about
+    let mut instances = BTreeMap::<String, (String, bool)>::new();
+    let mut services = BTreeMap::<String, (u16, String, Ipv4Addr)>::new();
+    let mut txts = BTreeMap::<String, Vec<String>>::new();
+    let mut hosts = BTreeMap::<String, Ipv4Addr>::new();
+    let mut peers = Vec::new();
+    let mut buf = [0u8; 9000];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(peers);
+        }
+        if last_query.is_none_or(|x| x.elapsed() >= QUERY_INTERVAL) {
+            let mut msg = Message::new(false, protos.len() as u16, 0);
+            for udp in &protos {
+                msg.question(&[SERVICE, proto(*udp), DOMAIN], TYPE_PTR);
+            }
+            sock.send_to(&msg.0, (GROUP, PORT))?;
+            last_query = Some(now);
+        }
+        sock.set_read_timeout(Some((deadline - now).min(QUERY_INTERVAL)))?;
+        let (len, from) = match sock.recv_from(&mut buf) {
+            Ok(x) => x,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(err) => return Err(err),
+        };
+        let Some(msg) = parse(&buf[..len]) else {
+            continue;
+        };
+        if !msg.response {
+            continue;
+        }
+        let IpAddr::V4(from) = from.ip() else {
+            continue;
+        };
+        for (name, data) in msg.records {
+            match data {
+                Data::Ptr(instance) => {
+                    let udp = protos
+                        .iter()
+                        .find(|udp| same(&name, &[SERVICE, proto(**udp), DOMAIN]));
+                    if let (Some(udp), Some(label)) = (udp, instance.first()) {
+                        instances.insert(key(&instance), (label.clone(), *udp));
+                    }
+                }
+                // the sender's address stands in for the target's in case it doesn't say
+                Data::Srv { port, target } => {
+                    services.insert(key(&name), (port, key(&target), from));
+                }
+                Data::Txt(txt) => {
+                    txts.insert(key(&name), txt);
+                }
+                Data::A(ip) => {
+                    hosts.insert(key(&name), ip);
+                }
+                Data::Other => {}
+            }
+        }
+        peers = instances
+            .iter()
+            .filter_map(|(key, (name, udp))| {
+                let (port, target, from) = services.get(key)?;
+                Some(Peer {
+                    name: name.clone(),
+                    udp: *udp,
+                    addr: (*hosts.get(target).unwrap_or(from), *port).into(),
+                    txt: txts.get(key).cloned().unwrap_or_default(),
+                })
+            })
+            .collect();
+        if done(&peers) {
+            return Ok(peers);
+        }
+    }
+}
+
+/// Look up the address of the instance called `name`, which has to have the given role
+pub fn resolve(
+    name: &str,
+    udp: bool,
+    role: Role,
+    interface: Option<Ipv4Addr>,
+) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    let found = |peer: &Peer| peer.name.eq_ignore_ascii_case(name);
+    let peers = browse(Some(udp), interface, RESOLVE_TIMEOUT, |peers| {
+        peers.iter().any(found)
+    })?;
+    let Some(peer) = peers.into_iter().find(found) else {
+        return Err(format!("no instance called {name:?} answered").into());
+    };
+    if peer.get("role") != Some(role.to_string().as_str()) {
+        return Err(format!("{name:?} doesn't {role}").into());
+    }
+    log::debug!("found {peer}");
+    Ok(peer.addr)
+}
+
+/// Print the listening instances that answer within `timeout`
+pub fn discover(
+    interface: Option<Ipv4Addr>,
+    timeout: Duration,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let peers = browse(None, interface, timeout, |_| false)?;
+    if peers.is_empty() {
+        return Err("no instances found".into());
+    }
+    for peer in peers {
+        println!("{peer}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::S16LE,
+    };
+
+    fn peer() -> Peer {
+        let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 4000));
+        Peer::new(
+            "phone".into(),
+            true,
+            addr,
+            Role::Play,
+            FORMAT,
+            Some(Codec::Pcm),
+        )
+    }
+
+    /// A message header with the given counts, followed by `body`
+    fn message(questions: u16, records: u16, body: &[u8]) -> Vec<u8> {
+        let mut msg = Message::new(true, questions, records).0;
+        msg.extend_from_slice(body);
+        msg
+    }
+
+    /// A received record, in zone file notation
+    fn describe((name, data): &(Vec<String>, Data)) -> String {
+        let data = match data {
+            Data::Ptr(x) => format!("PTR {}", key(x)),
+            Data::Srv { port, target } => format!("SRV {port} {}", key(target)),
+            Data::Txt(x) => format!("TXT {}", x.join(" ")),
+            Data::A(x) => format!("A {x}"),
+            Data::Other => "?".into(),
+        };
+        format!("{} {data}", key(name))
+    }
+
+    #[test]
+    fn advertisements_round_trip() {
+        let msg = parse(&response(&peer(), Ipv4Addr::new(192, 168, 1, 5), true)).unwrap();
+        assert!(msg.response);
+        assert!(msg.questions.is_empty());
+        let records: Vec<String> = msg.records.iter().map(describe).collect();
+        assert_eq!(
+            records,
+            [
+                "_services._dns-sd._udp.local PTR _ihatelatency._udp.local",
+                "_ihatelatency._udp.local PTR phone._ihatelatency._udp.local",
+                "phone._ihatelatency._udp.local SRV 4000 phone-ihatelatency.local",
+                "phone._ihatelatency._udp.local TXT role=play format=s16le rate=48000 channels=2 \
+                 codec=pcm",
+                "phone-ihatelatency.local A 192.168.1.5",
+            ]
+        );
+    }
+
+    #[test]
+    fn queries_round_trip() {
+        let mut msg = Message::new(false, 2, 0);
+        msg.question(&[SERVICE, "_udp", DOMAIN], TYPE_PTR);
+        msg.question(&["PHONE", SERVICE, "_tcp", DOMAIN], TYPE_ANY);
+        let msg = parse(&msg.0).unwrap();
+        assert!(!msg.response);
+        assert!(msg.records.is_empty());
+        assert!(same(&msg.questions[0].0, &[SERVICE, "_udp", DOMAIN]));
+        assert_eq!(msg.questions[0].1, TYPE_PTR);
+        // case doesn't matter
+        assert!(same(
+            &msg.questions[1].0,
+            &["phone", SERVICE, "_tcp", DOMAIN]
+        ));
+        assert_eq!(msg.questions[1].1, TYPE_ANY);
+    }
+
+    #[test]
+    fn truncates_oversized_labels() {
+        let long = "x".repeat(100);
+        let mut msg = Message::new(false, 1, 0);
+        msg.question(&[&long, DOMAIN], TYPE_A);
+        let msg = parse(&msg.0).unwrap();
+        assert_eq!(msg.questions[0].0, [&long[..MAX_LABEL], DOMAIN]);
+    }
+
+    #[test]
+    fn follows_compression_pointers() {
+        // "phone.local" at 12, then "x" pointing into it at 25, then a pointer to that at 29
+        let msg = message(0, 0, b"\x05phone\x05local\x00\x01x\xc0\x12\xc0\x19");
+        assert_eq!(
+            read_name(&msg, 12),
+            Some((vec!["phone".into(), "local".into()], 25))
+        );
+        assert_eq!(
+            read_name(&msg, 25),
+            Some((vec!["x".into(), "local".into()], 29))
+        );
+        // the name ends after the first pointer, wherever that leads
+        assert_eq!(
+            read_name(&msg, 29),
+            Some((vec!["x".into(), "local".into()], 31))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for body in [
+            // pointing at itself, and at each other
+            &b"\xc0\x0c"[..],
+            b"\x01a\xc0\x10\x01b\xc0\x0c",
+            // pointing past the end, and cut off
+            b"\xc0\xff",
+            b"\xc0",
+            b"\x05pho",
+            b"\x05phone",
+            // labels over 63 bytes, which use reserved length bits
+            &[0x40; 70],
+            &[0x80; 70],
+        ] {
+            let msg = message(0, 0, body);
+            assert_eq!(read_name(&msg, 12), None, "{body:02x?}");
+        }
+        // a long chain of pointers (each to the one before) looks like a loop too
+        let mut body = b"\x00".to_vec();
+        let mut prev = 12u16;
+        for _ in 0..200 {
+            body.extend((0xc000 | prev).to_be_bytes());
+            prev = 12 + body.len() as u16 - 2;
+        }
+        assert_eq!(read_name(&message(0, 0, &body), prev.into()), None);
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let msg = response(&peer(), Ipv4Addr::LOCALHOST, true);
+        for len in 0..msg.len() {
+            assert!(parse(&msg[..len]).is_none(), "parsed the first {len} bytes");
+        }
+        // or claiming more records than there are
+        let mut more = msg.clone();
+        more[7] += 1;
+        assert!(parse(&more).is_none());
+    }
+
+    #[test]
+    fn rejects_txt_strings_overrunning_their_record() {
+        let mut msg = Message::new(true, 0, 1);
+        msg.record(&["phone", DOMAIN], TYPE_TXT, true, |msg| {
+            msg.0.extend_from_slice(b"\x09role=play")
+        });
+        let mut msg = msg.0;
+        assert!(matches!(&parse(&msg).unwrap().records[0].1, Data::Txt(x) if x == &["role=play"]));
+        let len = msg.len();
+        msg[len - 10] = 20;
+        assert!(parse(&msg).is_none());
+    }
+
+    #[test]
+    fn gets_txt_values_by_key() {
+        let mut other = peer();
+        other.txt = vec![
+            "rolex=1".into(),
+            "role=play".into(),
+            "empty=".into(),
+            "flag".into(),
+        ];
+        assert_eq!(other.get("role"), Some("play"));
+        assert_eq!(other.get("empty"), Some(""));
+        assert_eq!(other.get("flag"), None);
+        assert_eq!(other.get("rate"), None);
+        assert_eq!(peer().get("rate"), Some("48000"));
+        assert_eq!(peer().get("codec"), Some("pcm"));
+    }
+}
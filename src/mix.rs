@@ -0,0 +1,260 @@
+//! Mixing multiple incoming streams into one
+//!
+//! Every source gets its own buffer, which is filled up a bit before the source starts playing
+//! to absorb network jitter. The mixer thread then sums the sources up in small blocks, only
+//! staying a little ahead of playback.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use ringbuf::traits::{Observer, Split};
+use ringbuf_blocking::BlockingRb;
+
+use crate::{format::StreamFormat, splice::Splicer, RingCons, RingProd};
+
+/// Length of the chunks the sources are mixed in
+const BLOCK_MS: usize = 5;
+/// How far ahead of playback to mix, on top of what the player buffers
+const AHEAD_MS: usize = 30;
+/// Amount of audio buffered for a source before it starts (or resumes) playing
+const PREBUFFER_MS: usize = 20;
+/// Sources buffering more than this get skipped ahead to `PREBUFFER_MS`
+const MAX_BUFFER_MS: usize = 100;
+const SOURCE_BUFFER_MS: usize = 500;
+/// Mixed audio louder than this gets compressed instead of clipped
+const KNEE: f32 = 0.8;
+
+/// Gain for the sources with a given IP address
+#[derive(Copy, Clone, Debug)]
+pub struct SourceGain {
+    ip: IpAddr,
+    gain: f32,
+}
+
+impl FromStr for SourceGain {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, gain) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected <ip>=<gain>, got {s:?}"))?;
+        Ok(Self {
+            ip: ip
+                .parse()
+                .map_err(|err| format!("invalid ip {ip:?}: {err}"))?,
+            gain: gain
+                .parse()
+                .map_err(|err| format!("invalid gain {gain:?}: {err}"))?,
+        })
+    }
+}
+
+/// Compress everything above `KNEE` so the result never exceeds 1.0
+fn soft_clip(x: f32) -> f32 {
+    let level = x.abs();
+    if level <= KNEE {
+        return x;
+    }
+    let over = (level - KNEE) / (1.0 - KNEE);
+    (KNEE + (1.0 - KNEE) * over.tanh()).copysign(x)
+}
+
+struct Source {
+    addr: SocketAddr,
+    cons: RingCons,
+    splicer: Splicer,
+    gain: f32,
+    playing: bool,
+}
+
+pub struct Mixer {
+    format: StreamFormat,
+    gains: Vec<SourceGain>,
+    /// Fixed amount of audio the player buffers, in frames
+    buffer_frames: usize,
+    sources: Mutex<Vec<Source>>,
+}
+
+impl Mixer {
+    pub fn new(format: StreamFormat, gains: Vec<SourceGain>, buffer_frames: Option<usize>) -> Self {
+        Self {
+            format,
+            gains,
+            buffer_frames: buffer_frames.unwrap_or(0),
+            sources: Mutex::new(Vec::new()),
+        }
+    }
+    fn bytes(&self, ms: usize) -> usize {
+        self.format.rate as usize * ms / 1000 * self.format.frame_bytes()
+    }
+    /// Add a source, returning the producer to push its audio into
+    ///
+    /// The source is removed once the producer is dropped and its remaining audio is played.
+    pub fn add(&self, addr: SocketAddr) -> RingProd {
+        let gain = self
+            .gains
+            .iter()
+            .find(|x| x.ip == addr.ip())
+            .map_or(1.0, |x| x.gain);
+        log::info!("mixing in {addr} (gain {gain})");
+        let (prod, cons) = BlockingRb::new(self.bytes(SOURCE_BUFFER_MS)).split();
+        self.sources.lock().unwrap().push(Source {
+            addr,
+            cons,
+            splicer: Splicer::new(self.format),
+            gain,
+            playing: false,
+        });
+        prod
+    }
+    /// Mix the sources into `prod` until it gets closed
+    pub fn run(&self, prod: &mut RingProd) {
+        let fmt = self.format.sample_format;
+        let frame_bytes = self.format.frame_bytes();
+        let block_bytes = self.bytes(BLOCK_MS);
+        let (ahead, prebuffer, max_buffer) = (
+            self.bytes(AHEAD_MS) + self.buffer_frames * frame_bytes,
+            self.bytes(PREBUFFER_MS),
+            self.bytes(MAX_BUFFER_MS),
+        );
+        let wait = Duration::from_millis(BLOCK_MS as u64) / 2;
+        let mut buf = vec![0u8; block_bytes];
+        let mut mixed = vec![0f32; block_bytes / fmt.bytes()];
+        while !prod.is_closed() {
+            if prod.occupied_len() >= ahead {
+                std::thread::sleep(wait);
+                continue;
+            }
+            mixed.fill(0.0);
+            let mut any = false;
+            self.sources.lock().unwrap().retain_mut(|src| {
+                let mut avail =
+                    src.cons.occupied_len() + src.splicer.pending_frames() * frame_bytes;
+                if !src.playing {
+                    if avail < prebuffer {
+                        return !src.cons.is_closed();
+                    }
+                    src.playing = true;
+                }
+                if avail > max_buffer {
+                    log::debug!("{} is too far ahead, skipping", src.addr);
+                    let frames = (avail - prebuffer) / frame_bytes;
+                    avail -= src.splicer.remove(&mut src.cons, frames) * frame_bytes;
+                }
+                if avail < block_bytes {
+                    if src.cons.is_closed() {
+                        log::info!("{} is done", src.addr);
+                        return false;
+                    }
+                    log::debug!("{} underran, prebuffering", src.addr);
+                    src.playing = false;
+                    return true;
+                }
+                src.splicer.pop(&mut src.cons, &mut buf);
+                for (x, sample) in mixed.iter_mut().zip(buf.chunks_exact(fmt.bytes())) {
+                    *x += fmt.decode(sample) * src.gain;
+                }
+                any = true;
+                true
+            });
+            if !any {
+                // let the player conceal the silence as it sees fit
+                std::thread::sleep(wait);
+                continue;
+            }
+            for (x, sample) in mixed.iter().zip(buf.chunks_exact_mut(fmt.bytes())) {
+                fmt.encode(soft_clip(*x), sample);
+            }
+            if !crate::push_all(prod, &buf) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use ringbuf::traits::{Consumer, Producer};
+
+    use super::*;
+    use crate::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::F32LE,
+    };
+
+    #[test]
+    fn soft_clips_only_above_the_knee() {
+        for i in -1000..=1000 {
+            let x = i as f32 / 100.0;
+            let y = soft_clip(x);
+            assert!(y.abs() <= 1.0, "{x} became {y}");
+            assert_eq!(soft_clip(-x), -y);
+            if x.abs() <= KNEE {
+                assert_eq!(y, x);
+            }
+            // never louder than before, and never turning a louder input quieter
+            assert!(y.abs() <= x.abs());
+            assert!(soft_clip(x + 0.01) >= y);
+        }
+        // continuous at the knee, with the same slope
+        let step = 1e-3;
+        assert!((soft_clip(KNEE + step) - (KNEE + step)).abs() < step * step * 10.0);
+        assert!(soft_clip(1e6) <= 1.0 && soft_clip(f32::INFINITY) == 1.0);
+    }
+
+    #[test]
+    fn parses_source_gains() {
+        let gain = |s: &str| s.parse::<SourceGain>().map(|x| (x.ip, x.gain));
+        assert_eq!(
+            gain("192.168.1.5=0.5"),
+            Ok(("192.168.1.5".parse().unwrap(), 0.5))
+        );
+        // IPv6 addresses work too, colons and all
+        assert_eq!(gain("fe80::1=2"), Ok(("fe80::1".parse().unwrap(), 2.0)));
+        for bad in [
+            "192.168.1.5",
+            "192.168.1.5=",
+            "192.168.1.5=loud",
+            "=0.5",
+            "[::1]=1",
+            "host=1",
+        ] {
+            assert!(gain(bad).is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn mixes_sources_with_their_gains() {
+        let addr = |ip: &str| SocketAddr::new(ip.parse().unwrap(), 4000);
+        let mixer = Mixer::new(FORMAT, vec!["127.0.0.2=2".parse().unwrap()], None);
+        let len = mixer.bytes(80);
+        for (ip, level) in [("127.0.0.1", 0.25f32), ("127.0.0.2", 0.125)] {
+            let mut prod = mixer.add(addr(ip));
+            prod.push_slice(&level.to_le_bytes().repeat(len / 4));
+        }
+        let (mut prod, mut cons) = BlockingRb::new(mixer.bytes(1000)).split();
+        let mut mixed = Vec::new();
+        std::thread::scope(|s| {
+            s.spawn(|| mixer.run(&mut prod));
+            let start = Instant::now();
+            // both sources end at the same time, so that's all of it
+            while mixed.len() < len && start.elapsed() < Duration::from_secs(5) {
+                let mut buf = [0u8; 4096];
+                let n = cons.pop_slice(&mut buf);
+                mixed.extend_from_slice(&buf[..n]);
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            drop(cons);
+        });
+        assert_eq!(mixed.len(), len);
+        assert!(mixed.chunks_exact(4).all(|x| x == 0.5f32.to_le_bytes()));
+    }
+}
@@ -0,0 +1,183 @@
+//! Multicast UDP, for sending one stream to any number of players at once
+//!
+//! A connecting recorder sends to the group, and listening players join it. Players on the same
+//! machine share the port, so every one of them gets its own copy of the stream.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    str::FromStr,
+};
+
+use clap::Args;
+use socket2::{Domain, Socket, Type};
+
+#[derive(Args, Copy, Clone, Debug)]
+pub struct MulticastOpts {
+    /// Interface to send from or join the group on, as its address (IPv4) or index (IPv6)
+    #[arg(long = "multicast-interface", value_name = "ADDR|INDEX")]
+    pub interface: Option<Interface>,
+    /// How many routers sent multicast packets may cross (defaults to 1, the local network)
+    #[arg(long = "multicast-ttl", value_name = "HOPS")]
+    pub ttl: Option<u32>,
+    /// Don't deliver sent multicast packets to players on this machine
+    #[arg(long)]
+    pub no_multicast_loop: bool,
+}
+
+/// Network interface to use for multicast
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interface {
+    Addr(Ipv4Addr),
+    Index(u32),
+}
+
+impl FromStr for Interface {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            Ok(Self::Addr(addr))
+        } else if let Ok(index) = s.parse() {
+            Ok(Self::Index(index))
+        } else {
+            Err(format!(
+                "{s:?} is neither an IPv4 address nor an interface index"
+            ))
+        }
+    }
+}
+
+impl MulticastOpts {
+    /// Check that the options make sense for sending to (or receiving from) `group`
+    pub fn check(&self, group: IpAddr) -> Result<(), String> {
+        if !group.is_multicast() {
+            if self.interface.is_some() || self.ttl.is_some() || self.no_multicast_loop {
+                return Err(format!("{group} isn't a multicast group"));
+            }
+            return Ok(());
+        }
+        match (group, self.interface) {
+            (IpAddr::V4(_), Some(Interface::Index(_))) => {
+                Err("IPv4 groups need the interface's address, not its index".into())
+            }
+            (IpAddr::V6(_), Some(Interface::Addr(_))) => {
+                Err("IPv6 groups need the interface's index, not its address".into())
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Socket sending to `group`
+    pub fn sender(&self, group: SocketAddr) -> io::Result<UdpSocket> {
+        let sock = Socket::new(Domain::for_address(group), Type::DGRAM, None)?;
+        let ttl = self.ttl.unwrap_or(1);
+        match group {
+            SocketAddr::V4(_) => {
+                sock.set_multicast_ttl_v4(ttl)?;
+                sock.set_multicast_loop_v4(!self.no_multicast_loop)?;
+                if let Some(Interface::Addr(addr)) = self.interface {
+                    sock.set_multicast_if_v4(&addr)?;
+                }
+            }
+            SocketAddr::V6(group) => {
+                sock.set_multicast_hops_v6(ttl)?;
+                sock.set_multicast_loop_v6(!self.no_multicast_loop)?;
+                sock.set_multicast_if_v6(self.index(group.scope_id()))?;
+            }
+        }
+        sock.connect(&group.into())?;
+        Ok(sock.into())
+    }
+    /// Socket that joined `group`
+    pub fn receiver(&self, group: SocketAddr) -> io::Result<UdpSocket> {
+        let sock = Socket::new(Domain::for_address(group), Type::DGRAM, None)?;
+        sock.set_reuse_address(true)?;
+        match group {
+            SocketAddr::V4(group) => {
+                // binding the group instead of any address keeps out other traffic to the port
+                sock.bind(&group.into())?;
+                let interface = match self.interface {
+                    Some(Interface::Addr(addr)) => addr,
+                    _ => Ipv4Addr::UNSPECIFIED,
+                };
+                sock.join_multicast_v4(group.ip(), &interface)?;
+            }
+            SocketAddr::V6(mut group) => {
+                // link-local groups can only be bound with the interface they're on
+                let index = self.index(group.scope_id());
+                group.set_scope_id(index);
+                sock.bind(&group.into())?;
+                sock.join_multicast_v6(group.ip(), index)?;
+            }
+        }
+        Ok(sock.into())
+    }
+    /// Index of the IPv6 interface to use, falling back to the group's scope
+    fn index(&self, scope_id: u32) -> u32 {
+        match self.interface {
+            Some(Interface::Index(index)) => index,
+            _ => scope_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(interface: Option<&str>, ttl: Option<u32>, no_multicast_loop: bool) -> MulticastOpts {
+        MulticastOpts {
+            interface: interface.map(|x| x.parse().unwrap()),
+            ttl,
+            no_multicast_loop,
+        }
+    }
+
+    #[test]
+    fn parses_interfaces() {
+        assert_eq!(
+            "192.168.1.2".parse(),
+            Ok(Interface::Addr(Ipv4Addr::new(192, 168, 1, 2)))
+        );
+        assert_eq!("3".parse(), Ok(Interface::Index(3)));
+        for s in ["", "eth0", "::1", "-1", "4294967296", "192.168.1"] {
+            assert!(s.parse::<Interface>().is_err(), "{s:?} parsed");
+        }
+    }
+
+    #[test]
+    fn checks_options_against_the_group() {
+        let (v4, v6): (IpAddr, IpAddr) = ("239.1.2.3".parse().unwrap(), "ff02::1".parse().unwrap());
+        for group in [v4, v6] {
+            assert_eq!(opts(None, None, false).check(group), Ok(()));
+            assert_eq!(opts(None, Some(5), true).check(group), Ok(()));
+        }
+        assert_eq!(opts(Some("10.0.0.1"), None, false).check(v4), Ok(()));
+        assert_eq!(opts(Some("2"), None, false).check(v6), Ok(()));
+        assert_eq!(
+            opts(Some("2"), None, false).check(v4),
+            Err("IPv4 groups need the interface's address, not its index".into())
+        );
+        assert_eq!(
+            opts(Some("10.0.0.1"), None, false).check(v6),
+            Err("IPv6 groups need the interface's index, not its address".into())
+        );
+    }
+
+    #[test]
+    fn rejects_multicast_options_for_unicast() {
+        for addr in ["127.0.0.1", "::1"] {
+            let addr: IpAddr = addr.parse().unwrap();
+            assert_eq!(opts(None, None, false).check(addr), Ok(()));
+            for opts in [
+                opts(Some("1"), None, false),
+                opts(None, Some(1), false),
+                opts(None, None, true),
+            ] {
+                assert_eq!(
+                    opts.check(addr),
+                    Err(format!("{addr} isn't a multicast group"))
+                );
+            }
+        }
+    }
+}
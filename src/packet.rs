@@ -0,0 +1,307 @@
+//! UDP audio packet framing
+//!
+//! Every audio datagram starts with a small header carrying a packet sequence number and the
+//! position (in frames) of its first frame, and only ever contains whole frames. This lets the
+//! receiver notice lost, reordered and duplicated packets and fill gaps with the exact amount of
+//! frames that went missing.
+
+use std::time::{Duration, Instant};
+
+const MAGIC: [u8; 4] = *b"ihl\x01";
+/// Sent by receivers to a listening sender to (re)subscribe to its stream
+pub const SUBSCRIBE: [u8; 4] = *b"ihl\x02";
+pub const HEADER_LEN: usize = 12;
+/// Keep datagrams under the typical MTU to avoid IP fragmentation
+const MAX_PACKET: usize = 1400;
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+/// This many late packets in a row mean the sender has restarted from scratch
+const MAX_STALE: u32 = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub seq: u32,
+    /// Position of the first frame of the packet in the stream
+    pub pos: u32,
+}
+
+impl Header {
+    pub fn write(&self, buf: &mut [u8]) {
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..8].copy_from_slice(&self.seq.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.pos.to_le_bytes());
+    }
+    pub fn parse(buf: &[u8]) -> Option<Self> {
+        if buf.len() < HEADER_LEN || buf[..4] != MAGIC {
+            return None;
+        }
+        Some(Self {
+            seq: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            pos: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
+    }
+}
+
+pub fn is_subscribe(buf: &[u8]) -> bool {
+    buf == SUBSCRIBE
+}
+
+/// Max payload size for a given frame size (always at least one frame)
+pub fn max_payload(frame_bytes: usize) -> usize {
+    ((MAX_PACKET - HEADER_LEN) / frame_bytes).max(1) * frame_bytes
+}
+
+/// Sender side packet numbering
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    seq: u32,
+    pos: u32,
+}
+
+impl Sequencer {
+    /// Get the header for the next packet, containing `frames` frames
+    pub fn next(&mut self, frames: usize) -> Header {
+        let ret = Header {
+            seq: self.seq,
+            pos: self.pos,
+        };
+        self.seq = self.seq.wrapping_add(1);
+        self.pos = self.pos.wrapping_add(frames as u32);
+        ret
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Packet should be played, after inserting this many frames to fill a gap
+    Accept {
+        gap: u32,
+    },
+    /// Packet arrived after its position was already played
+    Late,
+    Duplicate,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Stats {
+    received: u64,
+    lost: u64,
+    late: u64,
+    duplicate: u64,
+    resync: u64,
+}
+
+/// Receiver side packet ordering
+#[derive(Debug)]
+pub struct Reorderer {
+    /// Gaps bigger than this (in frames) are treated as a stream restart
+    max_gap: u32,
+    /// Next expected sequence number and frame position
+    next: Option<Header>,
+    /// Bitmap of received packets preceding `next.seq`, bit 0 being `next.seq - 1`
+    seen: u64,
+    /// Late or duplicate packets received in a row
+    stale: u32,
+    /// Whether sender restarts get flagged by a new session instead of being guessed, so old
+    /// (possibly replayed) packets never make us resync
+    sessions: bool,
+    stats: Stats,
+    last_stats: Stats,
+    stats_time: Instant,
+}
+
+impl Reorderer {
+    pub fn new(rate: u32, sessions: bool) -> Self {
+        Self {
+            max_gap: rate,
+            next: None,
+            seen: 0,
+            stale: 0,
+            sessions,
+            stats: Stats::default(),
+            last_stats: Stats::default(),
+            stats_time: Instant::now(),
+        }
+    }
+    pub fn packet(&mut self, header: Header, frames: usize) -> Verdict {
+        self.log_stats();
+        let next = Header {
+            seq: header.seq.wrapping_add(1),
+            pos: header.pos.wrapping_add(frames as u32),
+        };
+        let Some(expected) = self.next else {
+            self.next = Some(next);
+            self.seen = 1;
+            self.stats.received += 1;
+            return Verdict::Accept { gap: 0 };
+        };
+        let gap = header.pos.wrapping_sub(expected.pos) as i32;
+        let seq_gap = header.seq.wrapping_sub(expected.seq) as i32;
+        let restarted = if self.sessions {
+            // only a long outage can make an authenticated stream jump ahead this far
+            gap > self.max_gap as i32 || seq_gap > self.max_gap as i32
+        } else {
+            gap.unsigned_abs() > self.max_gap
+                || seq_gap.unsigned_abs() > self.max_gap
+                || self.stale >= MAX_STALE
+        };
+        if restarted {
+            log::info!("udp stream restarted, resyncing");
+            self.stats.resync += 1;
+            self.next = None;
+            self.stale = 0;
+            return self.packet(header, frames);
+        }
+        if seq_gap < 0 {
+            let age = seq_gap.unsigned_abs() - 1;
+            if age < 64 {
+                if self.seen & (1 << age) != 0 {
+                    self.stale += 1;
+                    self.stats.duplicate += 1;
+                    return Verdict::Duplicate;
+                }
+                self.seen |= 1 << age;
+            }
+        }
+        if gap < 0 || seq_gap < 0 {
+            self.stale += 1;
+            self.stats.late += 1;
+            log::debug!("late packet {} ({} frames)", header.seq, -gap);
+            return Verdict::Late;
+        }
+        // shift in the lost packets as unseen, and mark this one as seen
+        self.seen = self.seen.checked_shl(seq_gap as u32 + 1).unwrap_or(0) | 1;
+        if seq_gap > 0 {
+            self.stats.lost += seq_gap as u64;
+            log::debug!("lost {seq_gap} packets ({gap} frames)");
+        }
+        self.stats.received += 1;
+        self.stale = 0;
+        self.next = Some(next);
+        Verdict::Accept { gap: gap as u32 }
+    }
+    fn log_stats(&mut self) {
+        if self.stats_time.elapsed() < STATS_INTERVAL {
+            return;
+        }
+        self.stats_time = Instant::now();
+        let (cur, last) = (&self.stats, &self.last_stats);
+        if cur.lost != last.lost
+            || cur.late != last.late
+            || cur.duplicate != last.duplicate
+            || cur.resync != last.resync
+        {
+            log::info!(
+                "udp: {} received, {} lost, {} late, {} duplicate, {} resyncs",
+                cur.received,
+                cur.lost,
+                cur.late,
+                cur.duplicate,
+                cur.resync,
+            );
+        }
+        self.last_stats = self.stats;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+    const FRAMES: usize = 240;
+
+    /// Headers of a stream starting at the given counters
+    fn headers(seq: u32, pos: u32) -> impl Iterator<Item = Header> {
+        let mut sequencer = Sequencer { seq, pos };
+        std::iter::repeat_with(move || sequencer.next(FRAMES))
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            seq: 0x01020304,
+            pos: u32::MAX,
+        };
+        let mut buf = [0u8; HEADER_LEN];
+        header.write(&mut buf);
+        assert_eq!(Header::parse(&buf), Some(header));
+        assert_eq!(Header::parse(&buf[..HEADER_LEN - 1]), None);
+        buf[3] = 2;
+        assert_eq!(Header::parse(&buf), None);
+    }
+
+    #[test]
+    fn accepts_in_order_packets_across_wraparound() {
+        let mut reorderer = Reorderer::new(RATE, false);
+        for header in headers(u32::MAX - 5, u32::MAX - 3 * FRAMES as u32).take(20) {
+            assert_eq!(reorderer.packet(header, FRAMES), Verdict::Accept { gap: 0 });
+        }
+    }
+
+    #[test]
+    fn fills_gaps_and_drops_late_and_duplicate_packets() {
+        let mut reorderer = Reorderer::new(RATE, false);
+        let h: Vec<_> = headers(u32::MAX - 1, u32::MAX - FRAMES as u32)
+            .take(5)
+            .collect();
+        assert_eq!(reorderer.packet(h[0], FRAMES), Verdict::Accept { gap: 0 });
+        // two packets lost, across the wraparound
+        assert_eq!(
+            reorderer.packet(h[3], FRAMES),
+            Verdict::Accept {
+                gap: 2 * FRAMES as u32
+            }
+        );
+        // one of them shows up after its gap was already filled
+        assert_eq!(reorderer.packet(h[1], FRAMES), Verdict::Late);
+        assert_eq!(reorderer.packet(h[1], FRAMES), Verdict::Duplicate);
+        assert_eq!(reorderer.packet(h[3], FRAMES), Verdict::Duplicate);
+        assert_eq!(reorderer.packet(h[4], FRAMES), Verdict::Accept { gap: 0 });
+    }
+
+    #[test]
+    fn resyncs_when_the_sender_restarts() {
+        let mut reorderer = Reorderer::new(RATE, false);
+        let mut old = headers(1000, 1000 * FRAMES as u32);
+        for header in old.by_ref().take(10) {
+            reorderer.packet(header, FRAMES);
+        }
+        // a big jump means a restart
+        let mut new = headers(0, 0);
+        assert_eq!(
+            reorderer.packet(new.next().unwrap(), FRAMES),
+            Verdict::Accept { gap: 0 }
+        );
+        // and so do lots of stale packets in a row, even within the max gap
+        let mut reorderer = Reorderer::new(RATE, false);
+        for header in headers(100, 100 * FRAMES as u32).take(10) {
+            reorderer.packet(header, FRAMES);
+        }
+        let mut restarted = headers(90, 90 * FRAMES as u32);
+        for header in restarted.by_ref().take(MAX_STALE as usize) {
+            assert_ne!(reorderer.packet(header, FRAMES), Verdict::Accept { gap: 0 });
+        }
+        assert_eq!(
+            reorderer.packet(restarted.next().unwrap(), FRAMES),
+            Verdict::Accept { gap: 0 }
+        );
+    }
+
+    #[test]
+    fn never_resyncs_on_stale_packets_with_sessions() {
+        let mut reorderer = Reorderer::new(RATE, true);
+        let h: Vec<_> = headers(1000, 1000 * FRAMES as u32).take(300).collect();
+        assert_eq!(reorderer.packet(h[299], FRAMES), Verdict::Accept { gap: 0 });
+        // old packets stay late, no matter how old or how many
+        for header in &h[..299] {
+            assert_eq!(reorderer.packet(*header, FRAMES), Verdict::Late);
+        }
+        assert_eq!(
+            reorderer.packet(headers(0, 0).next().unwrap(), FRAMES),
+            Verdict::Late
+        );
+        // a long outage still gets skipped instead of filled in
+        let later = headers(2000, 2000 * FRAMES as u32).next().unwrap();
+        assert_eq!(reorderer.packet(later, FRAMES), Verdict::Accept { gap: 0 });
+    }
+}
@@ -1,88 +1,128 @@
 use std::time::Duration;
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use ringbuf::traits::{Consumer, Observer};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use ringbuf::traits::Observer;
 
-use crate::RingCons;
+use crate::{
+    conceal::{Concealer, Concealment},
+    device::{self, Direction},
+    format::{SampleFormat, StreamFormat},
+    jitter::{self, Action, Playout},
+    resample::Resampler,
+    splice::Splicer,
+    RingCons,
+};
+
+/// Turns the incoming audio into a steady stream, whatever the output
+pub struct Player {
+    cons: RingCons,
+    format: StreamFormat,
+    jitter: &'static jitter::Target,
+    log_level: log::LevelFilter,
+    concealer: Concealer,
+    playout: Playout,
+    resampler: Option<Resampler>,
+    splicer: Splicer,
+}
+
+impl Player {
+    /// The ring buffer's timeout is how long to wait for audio before concealing
+    pub fn new(
+        cons: RingCons,
+        format: StreamFormat,
+        jitter: &'static jitter::Target,
+        buffer_frames: Option<usize>,
+        max_drift_ppm: u32,
+        conceal: Concealment,
+    ) -> Self {
+        Self {
+            cons,
+            format,
+            jitter,
+            log_level: log::max_level(),
+            concealer: Concealer::new(conceal, format),
+            playout: Playout::new(jitter, buffer_frames, format, max_drift_ppm),
+            resampler: (max_drift_ppm != 0).then(|| Resampler::new(format)),
+            splicer: Splicer::new(format),
+        }
+    }
+    /// Fill `data` with audio (whole frames)
+    pub fn fill(&mut self, data: &mut [u8]) {
+        let frame_bytes = self.format.frame_bytes();
+        let cons = &mut self.cons;
+        match cons.wait_occupied(1) {
+            Ok(()) => {}
+            Err(err) => match err {
+                ringbuf_blocking::WaitError::Closed => {
+                    log::error!("ringbuf closed");
+                    std::process::exit(1);
+                }
+                ringbuf_blocking::WaitError::TimedOut => {}
+            },
+        }
+        let buffered = cons.occupied_len() / frame_bytes + self.splicer.pending_frames();
+        let needed = data.len() / frame_bytes;
+        if buffered < needed && self.log_level >= log::Level::Debug {
+            log::debug!(
+                "xrun ({} samples)",
+                (needed - buffered) * frame_bytes / self.format.sample_format.bytes()
+            );
+        }
+        if self.log_level >= log::Level::Trace {
+            log::trace!("buf {buffered} target {}", self.jitter.frames());
+        }
+        match self.playout.next(buffered, needed) {
+            Action::Play => {}
+            Action::Conceal => {
+                self.concealer.conceal(data);
+                return;
+            }
+            Action::Drop(frames) => {
+                self.splicer.remove(cons, frames);
+            }
+            Action::Insert(frames) => {
+                self.playout.inserted(self.splicer.insert(cons, frames));
+            }
+        }
+        let splicer = &mut self.splicer;
+        let len = if let Some(resampler) = &mut self.resampler {
+            resampler.process(data, self.playout.ratio(), |buf| splicer.pop(cons, buf))
+        } else {
+            splicer.pop(cons, data)
+        };
+        let (real, missing) = data.split_at_mut(len);
+        self.concealer.played(real);
+        self.concealer.conceal(missing);
+    }
+}
 
 pub fn main(
     mut cons: RingCons,
-    buffer_bytes: Option<usize>,
+    format: StreamFormat,
+    jitter: &'static jitter::Target,
+    buffer_frames: Option<usize>,
+    max_drift_ppm: u32,
     device_name: Option<String>,
+    conceal: Concealment,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let host = cpal::default_host();
-    let device = if let Some(name) = device_name {
-        host.output_devices()
-            .unwrap()
-            .find(|dev| {
-                let dev = dev.name();
-                log::info!("trying device {dev:?}");
-                matches!(dev, Ok(dev) if dev == name)
-            })
-            .expect("device not found")
-    } else {
-        host.default_output_device().unwrap()
-    };
-    let mut supported_configs_range = device.supported_output_configs().unwrap();
-    let supported_config = supported_configs_range
-        .find(|cfg| {
-            cfg.min_sample_rate().0 <= 48000
-                && cfg.max_sample_rate().0 >= 48000
-                && cfg.channels() == 2
-                && cfg.sample_format().is_int()
-                && cfg.sample_format().sample_size() == 2
-        })
-        .unwrap()
-        .with_sample_rate(cpal::SampleRate(48000));
-    let config = supported_config.into();
+    let device = device::find(device_name, Direction::Output)?;
+    let sample_format = device::cpal_format(format.sample_format);
+    let config = device::config(&device, format, Direction::Output)?;
     cons.set_timeout(Some(Duration::from_millis(10)));
-    let mut no_xrun_counter = 0;
-    let mut min_buf_size = usize::MAX;
-    let mut to_skip = 0;
-    let log_level = log::max_level();
-    let stream = device.build_output_stream(
+    let mut player = Player::new(cons, format, jitter, buffer_frames, max_drift_ppm, conceal);
+    let mut widened = Vec::new();
+    let stream = device.build_output_stream_raw(
         &config,
-        move |data: &mut [i16], _info: &cpal::OutputCallbackInfo| {
-            data.fill(0);
-            match cons.wait_occupied(1) {
-                Ok(()) => {}
-                Err(err) => match err {
-                    ringbuf_blocking::WaitError::Closed => {
-                        log::error!("ringbuf closed");
-                        std::process::exit(1);
-                    }
-                    ringbuf_blocking::WaitError::TimedOut => {
-                        return;
-                    }
-                },
-            }
-            if cons.occupied_len() < data.len() * 2 {
-                if log_level >= log::Level::Debug {
-                    log::debug!("xrun ({} samples)", data.len() - cons.occupied_len() / 2);
-                }
-                min_buf_size = usize::MAX;
-                to_skip = 0;
-                no_xrun_counter = 0;
-            } else if let Some(buffer_bytes) = buffer_bytes {
-                let extra_bytes = cons.occupied_len() - data.len() * 2;
-                cons.skip(extra_bytes.saturating_sub(buffer_bytes));
+        sample_format,
+        move |data: &mut cpal::Data, _info: &cpal::OutputCallbackInfo| {
+            let data = data.bytes_mut();
+            if format.sample_format == SampleFormat::S24LE {
+                widened.resize(data.len() / 4 * 3, 0);
+                player.fill(&mut widened);
+                device::widen_s24(&widened, data);
             } else {
-                no_xrun_counter += 1;
-                let buffer_size = cons.occupied_len() / 2 - data.len();
-                min_buf_size = min_buf_size.min(buffer_size);
-                cons.skip(to_skip.min(buffer_size) * 2);
-                if no_xrun_counter == 100 {
-                    no_xrun_counter = 0;
-                    to_skip = min_buf_size / 500;
-                    min_buf_size = usize::MAX;
-                }
-                if log_level >= log::Level::Trace {
-                    log::trace!("buf {buffer_size} ctr {no_xrun_counter}");
-                }
+                player.fill(data);
             }
-            cons.pop_slice(unsafe {
-                std::slice::from_raw_parts_mut(data.as_mut_ptr().cast(), data.len() * 2)
-            });
         },
         move |err| {
             log::error!("cpal: {err}");
@@ -0,0 +1,79 @@
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{conceal::Concealment, format::StreamFormat, jitter, play::Player, wav, RingCons};
+
+/// Amount of audio to write at once, like a sound card's period
+const PERIOD_MS: u32 = 5;
+/// How often to fill in the sizes in the WAV header
+const HEADER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Write the stream to the file at `path` (or stdout if `None`) in real time, as WAV if the
+/// file name ends with .wav and as raw audio otherwise
+pub fn main(
+    mut cons: RingCons,
+    format: StreamFormat,
+    jitter: &'static jitter::Target,
+    buffer_frames: Option<usize>,
+    max_drift_ppm: u32,
+    path: Option<PathBuf>,
+    conceal: Concealment,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (mut output, mut wav): (Box<dyn Write>, Option<File>) = match &path {
+        Some(path) => {
+            let mut file = File::create(path)?;
+            let wav = if path
+                .extension()
+                .is_some_and(|x| x.eq_ignore_ascii_case("wav"))
+            {
+                file.write_all(&wav::header(format, 0))?;
+                Some(file.try_clone()?)
+            } else {
+                None
+            };
+            (Box::new(file), wav)
+        }
+        None => (Box::new(io::stdout().lock()), None),
+    };
+    let name = path.map_or("stdout".into(), |x| x.display().to_string());
+    log::info!("writing {format} to {name}");
+    // we're the clock here, so there's no point in waiting for audio
+    cons.set_timeout(Some(Duration::ZERO));
+    let mut player = Player::new(cons, format, jitter, buffer_frames, max_drift_ppm, conceal);
+    let mut buf = vec![0u8; (format.rate * PERIOD_MS / 1000) as usize * format.frame_bytes()];
+    let start = Instant::now();
+    let mut frames = 0u64;
+    let mut written = 0u64;
+    let mut last_header = start;
+    loop {
+        player.fill(&mut buf);
+        output.write_all(&buf)?;
+        output.flush()?;
+        written += buf.len() as u64;
+        if let Some(file) = &mut wav {
+            if last_header.elapsed() >= HEADER_INTERVAL {
+                last_header = Instant::now();
+                update_header(file, format, written)?;
+            }
+        }
+        frames += (buf.len() / format.frame_bytes()) as u64;
+        let due = start + Duration::from_secs_f64(frames as f64 / f64::from(format.rate));
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Rewrite the header of a WAV file now that it has `data_len` bytes of audio (which is where
+/// the file's cursor is left)
+fn update_header(file: &mut File, format: StreamFormat, data_len: u64) -> io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&wav::header(
+        format,
+        u32::try_from(data_len).unwrap_or(u32::MAX),
+    ))?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use jack::{AudioOut, Client, Control, PortFlags, ProcessScope};
+
+use crate::{
+    conceal::Concealment,
+    format::StreamFormat,
+    jitter,
+    play::Player,
+    record_jack::{self, JackOpts},
+    RingCons,
+};
+
+pub fn main(
+    mut cons: RingCons,
+    format: StreamFormat,
+    jitter: &'static jitter::Target,
+    buffer_frames: Option<usize>,
+    max_drift_ppm: u32,
+    opts: JackOpts,
+    conceal: Concealment,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = record_jack::client(format)?;
+    let mut ports = (1..=format.channels)
+        .map(|i| client.register_port(&format!("out_{i}"), AudioOut::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let names = ports
+        .iter()
+        .map(|x| x.name())
+        .collect::<Result<Vec<_>, _>>()?;
+    let fmt = format.sample_format;
+    let mut buf = vec![0u8; client.buffer_size() as usize * format.frame_bytes()];
+    // the process thread must never wait for the network
+    cons.set_timeout(Some(Duration::ZERO));
+    let mut player = Player::new(cons, format, jitter, buffer_frames, max_drift_ppm, conceal);
+    let process = move |_: &Client, ps: &ProcessScope| -> Control {
+        let len = ps.n_frames() as usize * format.frame_bytes();
+        // only allocates if the period size grows
+        buf.resize(len, 0);
+        player.fill(&mut buf[..len]);
+        for (c, port) in ports.iter_mut().enumerate() {
+            let samples = buf[c * fmt.bytes()..len].chunks(format.frame_bytes());
+            for (x, sample) in port.as_mut_slice(ps).iter_mut().zip(samples) {
+                *x = fmt.decode(&sample[..fmt.bytes()]);
+            }
+        }
+        Control::Continue
+    };
+    let client = client.activate_async((), jack::contrib::ClosureProcessHandler::new(process))?;
+    record_jack::connect(client.as_client(), &names, PortFlags::IS_INPUT, &opts)?;
+    log::info!(
+        "playing {format} through jack (period {} frames)",
+        client.as_client().buffer_size()
+    );
+    std::thread::sleep(Duration::MAX);
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use pipewire::{
+    context::Context,
+    keys,
+    main_loop::MainLoop,
+    properties::properties,
+    spa,
+    stream::{Stream, StreamFlags, StreamRef},
+};
+
+use crate::{
+    conceal::Concealment, format::StreamFormat, jitter, play::Player, record::format_param,
+    RingCons,
+};
+
+/// Requested graph quantum, the smaller the lower the latency
+const LATENCY_MS: u32 = 5;
+
+struct Data {
+    player: Player,
+    frame_bytes: usize,
+}
+
+impl Data {
+    fn process(&mut self, stream: &StreamRef) {
+        let Some(mut buf) = stream.dequeue_buffer() else {
+            return;
+        };
+        // the graph may run at a bigger quantum than we asked for, so fill what it wants
+        let requested = match buf.requested() {
+            0 => usize::MAX,
+            frames => frames as usize,
+        };
+        let Some(samples) = buf.datas_mut().first_mut() else {
+            return;
+        };
+        let Some(data) = samples.data() else {
+            return;
+        };
+        let len = (data.len() / self.frame_bytes).min(requested) * self.frame_bytes;
+        self.player.fill(&mut data[..len]);
+        let chunk = samples.chunk_mut();
+        *chunk.offset_mut() = 0;
+        *chunk.stride_mut() = self.frame_bytes as i32;
+        *chunk.size_mut() = len as u32;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn main(
+    mut cons: RingCons,
+    format: StreamFormat,
+    jitter: &'static jitter::Target,
+    buffer_frames: Option<usize>,
+    max_drift_ppm: u32,
+    target: Option<String>,
+    as_source: Option<String>,
+    conceal: Concealment,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mainloop = MainLoop::new(None)?;
+    let context = Context::new(&mainloop)?;
+    let core = context.connect(None)?;
+
+    let quantum = (format.rate * LATENCY_MS / 1000) as usize;
+    let mut props = properties! {
+       keys::MEDIA_TYPE.as_bytes() => "Audio",
+       keys::MEDIA_CATEGORY.as_bytes() => "Playback",
+       keys::MEDIA_ROLE.as_bytes() => "Music",
+       keys::NODE_NAME.as_bytes() => as_source.as_deref().unwrap_or("ihatelatency"),
+       keys::NODE_LATENCY.as_bytes() => format!("{quantum}/{}", format.rate),
+    };
+    if let Some(target) = target {
+        props.insert(keys::TARGET_OBJECT.as_bytes(), target);
+    }
+    let mut flags = StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS;
+    if as_source.is_some() {
+        // apps record from us instead of us playing to a sink
+        props.insert(keys::MEDIA_CLASS.as_bytes(), "Audio/Source");
+        flags.remove(StreamFlags::AUTOCONNECT);
+    }
+
+    let stream = Stream::new(&core, "audio-playback", props)?;
+
+    // the callback runs on the realtime thread, which must never wait for the network
+    cons.set_timeout(Some(Duration::ZERO));
+    let player = Player::new(cons, format, jitter, buffer_frames, max_drift_ppm, conceal);
+    let _listener = stream
+        .add_local_listener_with_user_data(Data {
+            player,
+            frame_bytes: format.frame_bytes(),
+        })
+        .process(|stream, data| data.process(stream))
+        .register()?;
+
+    let values = format_param(format).map_err(|err| format!("pod serializer: {err}"))?;
+    let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+    stream.connect(spa::utils::Direction::Output, None, flags, &mut params)?;
+    match as_source {
+        Some(name) => log::info!("created source {name}"),
+        None => log::info!("playing {format} through pipewire"),
+    }
+
+    mainloop.run();
+
+    Ok(())
+}
@@ -0,0 +1,526 @@
+//! Pre-shared key encryption and authentication
+//!
+//! Both sides derive their keys from the contents of a secret file they share. Every UDP
+//! datagram is sealed with XChaCha20-Poly1305 under a random nonce, and anything that doesn't
+//! decrypt gets dropped. Inside the seal, each datagram carries its sender's random session id
+//! and a counter, and receivers keep a sliding window of the counters they've seen per session,
+//! dropping replayed datagrams and those too old to tell apart from replays. TCP connections start
+//! with a handshake: each side sends a random nonce, both derive a key per direction from the pair,
+//! and each proves it has the secret by sending an encrypted confirmation before any audio flows.
+//! The rest of the connection is a sequence of length-prefixed ChaCha20-Poly1305 records with
+//! counter nonces.
+//!
+//! There's no forward secrecy (whoever gets the secret later can decrypt recorded traffic). Only
+//! the last few sessions are remembered, and there's no handshake for UDP, so datagrams recorded
+//! from a session the receiver doesn't know (anymore) still get through once. Players stick to
+//! the session they're playing as long as it keeps sending, so those can't take over a stream.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, AeadCore, AeadInPlace, KeyInit, OsRng},
+    ChaCha20Poly1305, Nonce, Tag, XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+/// Secrets shorter than this are too easy to guess
+const MIN_SECRET_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Session id and counter at the start of every sealed datagram
+const COUNTER_LEN: usize = 16;
+/// Extra bytes a sealed datagram takes
+pub const OVERHEAD: usize = NONCE_LEN + COUNTER_LEN + TAG_LEN;
+/// How far a datagram's counter may lag behind the newest one of its session
+const REPLAY_WINDOW: u64 = 64;
+/// How many senders' sessions a receiver remembers
+const MAX_SESSIONS: usize = 16;
+/// Size of the random nonce each side sends at the start of a TCP connection
+const HANDSHAKE_LEN: usize = 32;
+/// First record on a TCP connection, proving that its sender has the key
+const CONFIRM: &[u8] = b"ihatelatency psk ok";
+/// Max amount of plaintext in a TCP record
+const MAX_RECORD: usize = 16384;
+
+#[derive(Copy, Clone)]
+pub struct Key {
+    /// Key for deriving the keys of TCP connections
+    tcp: [u8; 32],
+    /// Key for sealing datagrams
+    udp: [u8; 32],
+}
+
+// keep the key out of debug logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+impl Key {
+    /// Derive the keys from the contents of a file (surrounding whitespace is ignored)
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let secret = std::fs::read(path)?;
+        let secret = secret.trim_ascii();
+        if secret.len() < MIN_SECRET_LEN {
+            return Err(format!("the key file must hold at least {MIN_SECRET_LEN} bytes").into());
+        }
+        let (_, hkdf) = Hkdf::<Sha256>::extract(Some(b"ihatelatency psk"), secret);
+        let mut ret = Self {
+            tcp: [0u8; 32],
+            udp: [0u8; 32],
+        };
+        // 32 bytes is always a valid output length
+        hkdf.expand(b"tcp", &mut ret.tcp).unwrap();
+        hkdf.expand(b"udp", &mut ret.udp).unwrap();
+        Ok(ret)
+    }
+    /// Key for the records sent by the side that picked `from` to the one that picked `to`
+    fn record_key(&self, from: &[u8], to: &[u8]) -> ChaCha20Poly1305 {
+        let salt = [from, to].concat();
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), &self.tcp)
+            .expand(b"record", &mut key)
+            .unwrap();
+        ChaCha20Poly1305::new(&key.into())
+    }
+}
+
+/// Seals the datagrams of one sender
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+    session: u64,
+    counter: AtomicU64,
+}
+
+impl Sealer {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.udp.into()),
+            session: OsRng.next_u64(),
+            counter: AtomicU64::new(0),
+        }
+    }
+    /// Encrypt a datagram into `out`, returning the part of it to send
+    pub fn seal<'a>(&self, data: &[u8], out: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        let len = OVERHEAD + data.len();
+        if out.len() < len {
+            return Err(io::Error::other("datagram too large to encrypt"));
+        }
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let (head, body) = out[..len].split_at_mut(NONCE_LEN);
+        let (body, tag) = body.split_at_mut(COUNTER_LEN + data.len());
+        head.copy_from_slice(&nonce);
+        body[..8].copy_from_slice(&self.session.to_le_bytes());
+        body[8..COUNTER_LEN].copy_from_slice(&counter.to_le_bytes());
+        body[COUNTER_LEN..].copy_from_slice(data);
+        let sealed = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &[], body)
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        tag.copy_from_slice(&sealed);
+        Ok(&out[..len])
+    }
+}
+
+/// Counters seen recently in one session
+struct Window {
+    session: u64,
+    latest: u64,
+    /// Bitmap of the counters seen, bit 0 being `latest`
+    seen: u64,
+}
+
+impl Window {
+    /// Mark a counter as seen, returning false if it was seen already or is too old to tell
+    fn check(&mut self, counter: u64) -> bool {
+        if counter > self.latest {
+            self.seen = self
+                .seen
+                .checked_shl((counter - self.latest) as u32)
+                .unwrap_or(0)
+                | 1;
+            self.latest = counter;
+            return true;
+        }
+        let age = self.latest - counter;
+        if age >= REPLAY_WINDOW || self.seen & (1 << age) != 0 {
+            return false;
+        }
+        self.seen |= 1 << age;
+        true
+    }
+}
+
+/// Opens the datagrams of any number of senders, dropping replayed ones
+pub struct Opener {
+    cipher: XChaCha20Poly1305,
+    /// Least recently used first
+    sessions: Vec<Window>,
+}
+
+impl Opener {
+    pub fn new(key: &Key) -> Self {
+        Self {
+            cipher: XChaCha20Poly1305::new(&key.udp.into()),
+            sessions: Vec::new(),
+        }
+    }
+    /// Decrypt a datagram in place, returning its session and contents if it was sealed with
+    /// our key and hasn't been seen before
+    pub fn open<'a>(&mut self, data: &'a mut [u8]) -> Option<(u64, &'a mut [u8])> {
+        if data.len() < OVERHEAD {
+            return None;
+        }
+        let (nonce, body) = data.split_at_mut(NONCE_LEN);
+        let (body, tag) = body.split_at_mut(body.len() - TAG_LEN);
+        self.cipher
+            .decrypt_in_place_detached(XNonce::from_slice(nonce), &[], body, Tag::from_slice(tag))
+            .ok()?;
+        let (counter, body) = body.split_at_mut(COUNTER_LEN);
+        let session = u64::from_le_bytes(counter[..8].try_into().unwrap());
+        let counter = u64::from_le_bytes(counter[8..].try_into().unwrap());
+        match self.sessions.iter().position(|x| x.session == session) {
+            Some(i) => {
+                let mut window = self.sessions.remove(i);
+                let fresh = window.check(counter);
+                self.sessions.push(window);
+                if !fresh {
+                    return None;
+                }
+            }
+            None => {
+                if self.sessions.len() >= MAX_SESSIONS {
+                    self.sessions.remove(0);
+                }
+                self.sessions.push(Window {
+                    session,
+                    latest: counter,
+                    seen: 1,
+                });
+            }
+        }
+        Some((session, body))
+    }
+}
+
+/// Encrypt a datagram for sending if there's a key, using `buf` for the result
+pub fn seal<'a>(
+    sealer: Option<&Sealer>,
+    data: &'a [u8],
+    buf: &'a mut [u8],
+) -> io::Result<&'a [u8]> {
+    match sealer {
+        Some(sealer) => sealer.seal(data, buf),
+        None => Ok(data),
+    }
+}
+
+/// Decrypt a received datagram if there's a key, returning `None` if it's not from someone
+/// with the same key or was replayed, and its session otherwise
+pub fn open<'a>(
+    opener: Option<&mut Opener>,
+    data: &'a mut [u8],
+) -> Option<(Option<u64>, &'a mut [u8])> {
+    match opener {
+        Some(opener) => opener
+            .open(data)
+            .map(|(session, data)| (Some(session), data)),
+        None => Some((None, data)),
+    }
+}
+
+/// One direction of an encrypted TCP connection
+struct Records {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Records {
+    fn nonce(&mut self) -> Nonce {
+        let mut nonce = Nonce::default();
+        nonce[..8].copy_from_slice(&self.counter.to_le_bytes());
+        self.counter += 1;
+        nonce
+    }
+}
+
+/// TCP connection that is encrypted if there's a key, and passed through as is otherwise
+pub struct Stream<'a> {
+    conn: &'a TcpStream,
+    session: Option<(Records, Records)>,
+    /// Decrypted data that hasn't been read yet (starting at `pos`)
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> Stream<'a> {
+    /// Set up a fresh connection, failing if the other side doesn't have the same key
+    pub fn new(conn: &'a TcpStream, key: Option<&Key>) -> io::Result<Self> {
+        let mut ret = Self {
+            conn,
+            session: None,
+            plain: Vec::new(),
+            pos: 0,
+        };
+        let Some(key) = key else {
+            return Ok(ret);
+        };
+        let mut ours = [0u8; HANDSHAKE_LEN];
+        OsRng.fill_bytes(&mut ours);
+        let mut theirs = [0u8; HANDSHAKE_LEN];
+        (&mut ret.conn).write_all(&ours)?;
+        (&mut ret.conn).read_exact(&mut theirs)?;
+        // otherwise our own records could be reflected back at us
+        if theirs == ours {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "peer echoed our handshake",
+            ));
+        }
+        let send = Records {
+            cipher: key.record_key(&ours, &theirs),
+            counter: 0,
+        };
+        let recv = Records {
+            cipher: key.record_key(&theirs, &ours),
+            counter: 0,
+        };
+        ret.session = Some((send, recv));
+        ret.write_all(CONFIRM)?;
+        let mut confirm = [0u8; CONFIRM.len()];
+        match ret.read_exact(&mut confirm) {
+            Ok(()) if confirm == CONFIRM => Ok(ret),
+            Err(err) if err.kind() != io::ErrorKind::InvalidData => Err(err),
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "peer doesn't have the key",
+            )),
+        }
+    }
+}
+
+impl Write for Stream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some((send, _)) = &mut self.session else {
+            return self.conn.write(buf);
+        };
+        // no point in sending empty records
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(MAX_RECORD);
+        let mut record = Vec::with_capacity(2 + len + TAG_LEN);
+        record.extend_from_slice(&((len + TAG_LEN) as u16).to_le_bytes());
+        record.extend_from_slice(&buf[..len]);
+        let nonce = send.nonce();
+        let tag = send
+            .cipher
+            .encrypt_in_place_detached(&nonce, &[], &mut record[2..])
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        record.extend_from_slice(&tag);
+        self.conn.write_all(&record)?;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+impl Read for Stream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((_, recv)) = &mut self.session else {
+            return self.conn.read(buf);
+        };
+        while self.pos == self.plain.len() {
+            let mut len = [0u8; 2];
+            match self.conn.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(err) => return Err(err),
+            }
+            let len = usize::from(u16::from_le_bytes(len));
+            if len < TAG_LEN {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record too short",
+                ));
+            }
+            self.plain.resize(len, 0);
+            self.conn.read_exact(&mut self.plain)?;
+            let tag = Tag::clone_from_slice(&self.plain[len - TAG_LEN..]);
+            self.plain.truncate(len - TAG_LEN);
+            let nonce = recv.nonce();
+            if recv
+                .cipher
+                .decrypt_in_place_detached(&nonce, &[], &mut self.plain, &tag)
+                .is_err()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "record failed authentication",
+                ));
+            }
+            self.pos = 0;
+        }
+        let len = buf.len().min(self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..][..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    fn key() -> Key {
+        Key {
+            tcp: [1u8; 32],
+            udp: [2u8; 32],
+        }
+    }
+
+    /// Both ends of a loopback TCP connection
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (conn, listener.accept().unwrap().0)
+    }
+
+    #[test]
+    fn opens_sealed_datagrams() {
+        let sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+        let mut buf = [0u8; 64];
+        let mut sealed = sealer.seal(b"hello", &mut buf).unwrap().to_vec();
+        let (session, data) = opener.open(&mut sealed).unwrap();
+        assert_eq!(session, sealer.session);
+        assert_eq!(data, b"hello");
+    }
+
+    #[test]
+    fn drops_tampered_datagrams() {
+        let sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+        let mut buf = [0u8; 64];
+        let mut sealed = sealer.seal(b"hello", &mut buf).unwrap().to_vec();
+        sealed[NONCE_LEN + 3] ^= 1;
+        assert!(opener.open(&mut sealed).is_none());
+        assert!(opener.open(&mut [0u8; OVERHEAD - 1]).is_none());
+    }
+
+    #[test]
+    fn drops_replays() {
+        let sealer = Sealer::new(&key());
+        let mut opener = Opener::new(&key());
+        let mut buf = [0u8; 64];
+        let packets: Vec<_> = (0..100u8)
+            .map(|x| sealer.seal(&[x], &mut buf).unwrap().to_vec())
+            .collect();
+        // reordered packets still get through, once
+        assert!(opener.open(&mut packets[1].clone()).is_some());
+        assert!(opener.open(&mut packets[0].clone()).is_some());
+        assert!(opener.open(&mut packets[0].clone()).is_none());
+        assert!(opener.open(&mut packets[1].clone()).is_none());
+        assert!(opener.open(&mut packets[99].clone()).is_some());
+        // within the window, but seen already
+        assert!(opener.open(&mut packets[99].clone()).is_none());
+        // not seen, but too old to tell
+        assert!(opener.open(&mut packets[2].clone()).is_none());
+        assert!(opener.open(&mut packets[50].clone()).is_some());
+    }
+
+    #[test]
+    fn tracks_sessions_separately() {
+        let (a, b) = (Sealer::new(&key()), Sealer::new(&key()));
+        let mut opener = Opener::new(&key());
+        let mut buf = [0u8; 64];
+        let mut from_a = a.seal(b"a", &mut buf).unwrap().to_vec();
+        let mut from_b = b.seal(b"b", &mut buf).unwrap().to_vec();
+        assert!(opener.open(&mut from_a.clone()).is_some());
+        assert!(opener.open(&mut from_b.clone()).is_some());
+        assert!(opener.open(&mut from_a).is_none());
+        assert!(opener.open(&mut from_b).is_none());
+    }
+
+    #[test]
+    fn streams_with_the_same_key() {
+        let (a, b) = tcp_pair();
+        let data: Vec<u8> = (0..3 * MAX_RECORD).map(|x| x as u8).collect();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut stream = Stream::new(&a, Some(&key())).unwrap();
+                stream.write_all(&data).unwrap();
+                stream.read_exact(&mut [0u8; 2]).unwrap();
+            });
+            let mut stream = Stream::new(&b, Some(&key())).unwrap();
+            let mut received = vec![0u8; data.len()];
+            stream.read_exact(&mut received).unwrap();
+            assert_eq!(received, data);
+            // and the other way round
+            stream.write_all(b"ok").unwrap();
+        });
+    }
+
+    #[test]
+    fn rejects_a_different_key() {
+        let (a, b) = tcp_pair();
+        let other = Key {
+            tcp: [3u8; 32],
+            ..key()
+        };
+        std::thread::scope(|s| {
+            let theirs = s.spawn(|| Stream::new(&a, Some(&other)).map(|_| ()));
+            for err in [
+                Stream::new(&b, Some(&key())).map(|_| ()).unwrap_err(),
+                theirs.join().unwrap().unwrap_err(),
+            ] {
+                assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            }
+        });
+    }
+
+    #[test]
+    fn rejects_tampered_records() {
+        // relay between the two sides, flipping a bit of the first record after the handshake
+        let (a, relay_a) = tcp_pair();
+        let (relay_b, b) = tcp_pair();
+        let relay = |from: &TcpStream, to: &TcpStream, len: usize| {
+            let mut buf = vec![0u8; len];
+            (&*from).read_exact(&mut buf).unwrap();
+            (&*to).write_all(&buf).unwrap();
+        };
+        let confirm_len = 2 + CONFIRM.len() + TAG_LEN;
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut stream = Stream::new(&a, Some(&key())).unwrap();
+                stream.write_all(b"hello").unwrap();
+            });
+            let server = s.spawn(|| {
+                let mut stream = Stream::new(&b, Some(&key()))?;
+                stream.read_exact(&mut [0u8; 5])
+            });
+            for len in [HANDSHAKE_LEN, confirm_len] {
+                relay(&relay_a, &relay_b, len);
+                relay(&relay_b, &relay_a, len);
+            }
+            let mut record = [0u8; 2 + 5 + TAG_LEN];
+            (&relay_a).read_exact(&mut record).unwrap();
+            record[4] ^= 1;
+            (&relay_b).write_all(&record).unwrap();
+            let err = server.join().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -16,7 +16,59 @@ use pipewire::{
 };
 use ringbuf::traits::Producer;
 
-use crate::RingProd;
+use crate::{
+    format::{SampleFormat, StreamFormat},
+    RingProd,
+};
+
+fn spa_format(fmt: SampleFormat) -> spa::param::audio::AudioFormat {
+    match fmt {
+        SampleFormat::S16LE => spa::param::audio::AudioFormat::S16LE,
+        SampleFormat::S24LE => spa::param::audio::AudioFormat::S24LE,
+        SampleFormat::S32LE => spa::param::audio::AudioFormat::S32LE,
+        SampleFormat::F32LE => spa::param::audio::AudioFormat::F32LE,
+    }
+}
+
+/// Serialized `EnumFormat` param asking for exactly `format`
+pub fn format_param(format: StreamFormat) -> Result<Vec<u8>, spa::pod::serialize::GenError> {
+    let pod_object = object! {
+        spa::utils::SpaTypes::ObjectParamFormat,
+        spa::param::ParamType::EnumFormat,
+        property!(
+            spa::param::format::FormatProperties::MediaType,
+            Id,
+            spa::param::format::MediaType::Audio
+        ),
+        property!(
+            spa::param::format::FormatProperties::MediaSubtype,
+            Id,
+            spa::param::format::MediaSubtype::Raw
+        ),
+        property!(
+           spa::param::format::FormatProperties::AudioFormat,
+           Id,
+           spa_format(format.sample_format)
+        ),
+        property!(
+           spa::param::format::FormatProperties::AudioRate,
+           Int,
+           format.rate as i32
+        ),
+        property!(
+           spa::param::format::FormatProperties::AudioChannels,
+           Int,
+           format.channels.into()
+        ),
+    };
+
+    Ok(spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &spa::pod::Value::Object(pod_object),
+    )?
+    .0
+    .into_inner())
+}
 
 struct Data {
     prod: RingProd,
@@ -81,6 +133,7 @@ impl Data {
 
 struct Global {
     node_name: String,
+    format: StreamFormat,
     obj: Option<u32>,
     stream: Stream,
 }
@@ -100,41 +153,8 @@ impl Global {
         if self.obj.is_some() {
             return;
         }
-        let pod_object = object! {
-            spa::utils::SpaTypes::ObjectParamFormat,
-            spa::param::ParamType::EnumFormat,
-            property!(
-                spa::param::format::FormatProperties::MediaType,
-                Id,
-                spa::param::format::MediaType::Audio
-            ),
-            property!(
-                spa::param::format::FormatProperties::MediaSubtype,
-                Id,
-                spa::param::format::MediaSubtype::Raw
-            ),
-            property!(
-               spa::param::format::FormatProperties::AudioFormat,
-               Id,
-               spa::param::audio::AudioFormat::S16LE
-            ),
-            property!(
-               spa::param::format::FormatProperties::AudioRate,
-               Int,
-               48000
-            ),
-            property!(
-               spa::param::format::FormatProperties::AudioChannels,
-               Int,
-               2
-            ),
-        };
-
-        let values = match spa::pod::serialize::PodSerializer::serialize(
-            std::io::Cursor::new(Vec::new()),
-            &spa::pod::Value::Object(pod_object),
-        ) {
-            Ok(x) => x.0.into_inner(),
+        let values = match format_param(self.format) {
+            Ok(x) => x,
             Err(err) => {
                 log::error!("pod serializer: {err}");
                 return;
@@ -168,17 +188,27 @@ impl Global {
     }
 }
 
-pub fn main(node_name: String, prod: RingProd) -> Result<(), Box<dyn std::error::Error>> {
+/// Record from the node called `node_name`, or from a new sink called that if `virtual_sink`
+pub fn main(
+    node_name: String,
+    virtual_sink: bool,
+    format: StreamFormat,
+    prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
     let mainloop = MainLoop::new(None)?;
     let context = Context::new(&mainloop)?;
     let core = context.connect(None)?;
     let registry = core.get_registry()?;
 
-    let props = properties! {
+    let mut props = properties! {
        keys::MEDIA_TYPE.as_bytes() => "Audio",
        keys::MEDIA_CATEGORY.as_bytes() => "Capture",
        keys::MEDIA_ROLE.as_bytes() => "Music",
     };
+    if virtual_sink {
+        props.insert(keys::MEDIA_CLASS.as_bytes(), "Audio/Sink");
+        props.insert(keys::NODE_NAME.as_bytes(), node_name.as_str());
+    }
 
     let stream = Stream::new(&core, "audio-capture", props)?;
 
@@ -191,8 +221,24 @@ pub fn main(node_name: String, prod: RingProd) -> Result<(), Box<dyn std::error:
         .process(|stream, data| data.process(stream))
         .register()?;
 
+    if virtual_sink {
+        // apps connect to us, so there's nothing to look for
+        let values = format_param(format).map_err(|err| format!("pod serializer: {err}"))?;
+        let mut params = [spa::pod::Pod::from_bytes(&values).unwrap()];
+        stream.connect(
+            spa::utils::Direction::Input,
+            None,
+            StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+        log::info!("created sink {node_name}");
+        mainloop.run();
+        return Ok(());
+    }
+
     let global = Rc::new(RefCell::new(Global {
         node_name,
+        format,
         obj: None,
         stream,
     }));
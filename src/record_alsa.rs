@@ -1,40 +1,68 @@
 use alsa::pcm::*;
 use alsa::{Direction, ValueOr};
-use ringbuf::traits::Producer;
 
-use crate::RingProd;
+use crate::{
+    format::{SampleFormat, StreamFormat},
+    RingProd,
+};
 
-pub fn main(device_name: String, mut prod: RingProd) -> Result<(), Box<dyn std::error::Error>> {
-    let pcm = PCM::new(&device_name, Direction::Playback, false)?;
+/// Length of a capture period, the smaller the lower the latency
+const PERIOD_MS: u32 = 5;
+const PERIODS: u32 = 4;
+
+fn alsa_format(fmt: SampleFormat) -> Format {
+    match fmt {
+        SampleFormat::S16LE => Format::S16LE,
+        SampleFormat::S24LE => Format::S243LE,
+        SampleFormat::S32LE => Format::S32LE,
+        SampleFormat::F32LE => Format::FloatLE,
+    }
+}
+
+pub fn main(
+    device_name: String,
+    format: StreamFormat,
+    mut prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pcm = PCM::new(&device_name, Direction::Capture, false)?;
     {
         let hwp = HwParams::any(&pcm)?;
-        hwp.set_channels(2)?;
-        hwp.set_rate(48000, ValueOr::Nearest)?;
-        hwp.set_format(Format::S16LE)?;
+        hwp.set_channels(format.channels.into())?;
+        hwp.set_rate(format.rate, ValueOr::Nearest)?;
+        hwp.set_format(alsa_format(format.sample_format))?;
         hwp.set_access(Access::RWInterleaved)?;
+        let period = (format.rate * PERIOD_MS / 1000).into();
+        hwp.set_period_size_near(period, ValueOr::Nearest)?;
+        hwp.set_buffer_size_near(period * Frames::from(PERIODS))?;
         pcm.hw_params(&hwp)?;
     }
+    {
+        let hwp = pcm.hw_params_current()?;
+        if hwp.get_rate()? != format.rate {
+            return Err(format!("device doesn't support {format}").into());
+        }
+        log::info!(
+            "capturing {format} from {device_name} (period {} frames, buffer {} frames)",
+            hwp.get_period_size()?,
+            hwp.get_buffer_size()?
+        );
+    }
     pcm.start()?;
-    let io = pcm.io_i16()?;
-    let mut buf = [0i16; 8192];
+    let io = pcm.io_bytes();
+    let frame_bytes = format.frame_bytes();
+    let mut buf = vec![0u8; 8192 / frame_bytes * frame_bytes];
     loop {
-        let size = io.readi(&mut buf)?;
-        let size = size * 2;
-        let mut pushed = 0;
-        println!(
-            "avg {} len {size}",
-            buf.iter().map(|x| isize::from(*x)).sum::<isize>() / size as isize / 2
-        );
-        let buf = unsafe { std::slice::from_raw_parts(buf.as_ptr().cast(), buf.len() / 2) };
-        while pushed < size {
-            match prod.wait_vacant(size - pushed) {
-                Ok(()) => {}
-                Err(err) => match err {
-                    ringbuf_blocking::WaitError::TimedOut => continue,
-                    ringbuf_blocking::WaitError::Closed => panic!("ringbuf closed"),
-                },
+        let frames = match io.readi(&mut buf) {
+            Ok(x) => x,
+            Err(err) => {
+                // overruns just lose some audio, so start over
+                log::debug!("alsa: {err}");
+                pcm.try_recover(err, true)?;
+                continue;
             }
-            pushed += prod.push_slice(buf);
+        };
+        if !crate::push_all(&mut prod, &buf[..frames * frame_bytes]) {
+            return Err("ringbuf closed".into());
         }
     }
 }
@@ -0,0 +1,44 @@
+use std::sync::mpsc;
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+
+use crate::{
+    device::{self, Direction},
+    format::{SampleFormat, StreamFormat},
+    RingProd,
+};
+
+pub fn main(
+    device_name: Option<String>,
+    format: StreamFormat,
+    mut prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let device = device::find(device_name, Direction::Input)?;
+    let sample_format = device::cpal_format(format.sample_format);
+    let config = device::config(&device, format, Direction::Input)?;
+    log::info!("capturing {format} from {:?}", device.name());
+    let (closed_tx, closed_rx) = mpsc::channel();
+    let mut narrowed = Vec::new();
+    let stream = device.build_input_stream_raw(
+        &config,
+        sample_format,
+        move |data: &cpal::Data, _info: &cpal::InputCallbackInfo| {
+            let mut data = data.bytes();
+            if format.sample_format == SampleFormat::S24LE {
+                narrowed.resize(data.len() / 4 * 3, 0);
+                device::narrow_s24(data, &mut narrowed);
+                data = &narrowed;
+            }
+            if !crate::push_all(&mut prod, data) {
+                let _ = closed_tx.send(());
+            }
+        },
+        move |err| {
+            log::error!("cpal: {err}");
+        },
+        None, // blocking
+    )?;
+    stream.play()?;
+    let _ = closed_rx.recv();
+    Err("ringbuf closed".into())
+}
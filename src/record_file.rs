@@ -0,0 +1,84 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use ringbuf::traits::Observer;
+
+use crate::{format::StreamFormat, wav, RingProd};
+
+/// Amount of audio to read at once
+const CHUNK_MS: u32 = 5;
+/// Time the network thread gets to send the last bit of audio it took out of the ring buffer
+const DRAIN_GRACE: Duration = Duration::from_millis(200);
+
+/// Read raw or WAV audio from the file at `path` (or stdin if `None`) in real time, returning
+/// once all of it has been handed to the network
+pub fn main(
+    path: Option<PathBuf>,
+    format: StreamFormat,
+    mut prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input: Box<dyn Read> = match &path {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin().lock()),
+    };
+    let name = path.map_or("stdin".into(), |x| x.display().to_string());
+    let frame_bytes = format.frame_bytes();
+    let mut buf = vec![0u8; (format.rate * CHUNK_MS / 1000) as usize * frame_bytes];
+    // anything that doesn't start like a WAV file is raw audio
+    let mut len = read_full(&mut input, &mut buf[..wav::MAGIC.len()])?;
+    if buf[..len] == wav::MAGIC {
+        wav::read_header(&mut input, format)?;
+        len = 0;
+        log::info!("reading {format} WAV from {name}");
+    } else {
+        log::info!("reading raw {format} from {name}");
+    }
+    let start = Instant::now();
+    let mut frames = 0u64;
+    loop {
+        len += read_full(&mut input, &mut buf[len..])?;
+        let whole = len / frame_bytes * frame_bytes;
+        if whole == 0 {
+            break;
+        }
+        if !crate::push_all(&mut prod, &buf[..whole]) {
+            return Err("ringbuf closed".into());
+        }
+        if whole < buf.len() {
+            // end of input
+            break;
+        }
+        len = 0;
+        // don't get ahead of the audio's own clock, so files don't get sent all at once
+        frames += (whole / frame_bytes) as u64;
+        let due = start + Duration::from_secs_f64(frames as f64 / f64::from(format.rate));
+        std::thread::sleep(due.saturating_duration_since(Instant::now()));
+    }
+    log::info!("end of {name}");
+    while !prod.is_empty() {
+        if prod.is_closed() {
+            return Err("ringbuf closed".into());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    std::thread::sleep(DRAIN_GRACE);
+    Ok(())
+}
+
+/// Fill `buf` unless the input ends first, returning the amount of bytes read
+fn read_full(input: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(x) => len += x,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
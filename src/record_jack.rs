@@ -0,0 +1,109 @@
+use std::sync::mpsc;
+
+use clap::Args;
+use jack::{AudioIn, Client, ClientOptions, Control, PortFlags, PortSpec, ProcessScope};
+use ringbuf::traits::{Observer, Producer};
+
+use crate::{format::StreamFormat, RingProd};
+
+#[derive(Args, Clone, Debug)]
+pub struct JackOpts {
+    /// Ports to connect to, as a regex matched against full port names (jack backend, defaults
+    /// to the physical ports)
+    #[arg(long, value_name = "REGEX")]
+    pub connect: Option<String>,
+    /// Leave the ports unconnected (jack backend)
+    #[arg(long, conflicts_with = "connect")]
+    pub no_connect: bool,
+}
+
+/// Open a client whose sample rate matches `format`
+pub fn client(format: StreamFormat) -> Result<Client, Box<dyn std::error::Error>> {
+    let (client, _status) = Client::new("ihatelatency", ClientOptions::NO_START_SERVER)?;
+    if client.sample_rate() != format.rate {
+        return Err(format!("jack runs at {}Hz, not {format}", client.sample_rate()).into());
+    }
+    Ok(client)
+}
+
+/// Connect `ours` one-to-one to the ports picked by `opts`, with `flags` being the flags the
+/// other ports need to have
+pub fn connect(
+    client: &Client,
+    ours: &[String],
+    flags: PortFlags,
+    opts: &JackOpts,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if opts.no_connect {
+        return Ok(());
+    }
+    let flags = match opts.connect {
+        Some(_) => flags,
+        None => flags | PortFlags::IS_PHYSICAL,
+    };
+    let theirs = client.ports(
+        opts.connect.as_deref(),
+        Some(AudioIn::default().jack_port_type()),
+        flags,
+    );
+    if theirs.is_empty() {
+        log::warn!("no ports to connect to");
+    }
+    for (ours, theirs) in ours.iter().zip(&theirs) {
+        log::info!("connecting {ours} to {theirs}");
+        if flags.contains(PortFlags::IS_OUTPUT) {
+            client.connect_ports_by_name(theirs, ours)?;
+        } else {
+            client.connect_ports_by_name(ours, theirs)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn main(
+    opts: JackOpts,
+    format: StreamFormat,
+    mut prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let client = client(format)?;
+    let ports = (1..=format.channels)
+        .map(|i| client.register_port(&format!("in_{i}"), AudioIn::default()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let names = ports
+        .iter()
+        .map(|x| x.name())
+        .collect::<Result<Vec<_>, _>>()?;
+    let fmt = format.sample_format;
+    let mut buf = vec![0u8; client.buffer_size() as usize * format.frame_bytes()];
+    let (closed_tx, closed_rx) = mpsc::channel();
+    let process = move |_: &Client, ps: &ProcessScope| -> Control {
+        let len = ps.n_frames() as usize * format.frame_bytes();
+        // only allocates if the period size grows
+        buf.resize(len, 0);
+        for (c, port) in ports.iter().enumerate() {
+            let samples = buf[c * fmt.bytes()..len].chunks_mut(format.frame_bytes());
+            for (x, sample) in port.as_slice(ps).iter().zip(samples) {
+                fmt.encode(*x, &mut sample[..fmt.bytes()]);
+            }
+        }
+        if prod.is_closed() {
+            let _ = closed_tx.send(());
+            return Control::Quit;
+        }
+        // the process thread can't wait for the network, so drop whatever doesn't fit
+        if prod.vacant_len() < len {
+            log::debug!("ringbuf full, dropping audio");
+        } else {
+            prod.push_slice(&buf[..len]);
+        }
+        Control::Continue
+    };
+    let client = client.activate_async((), jack::contrib::ClosureProcessHandler::new(process))?;
+    connect(client.as_client(), &names, PortFlags::IS_OUTPUT, &opts)?;
+    log::info!(
+        "capturing {format} from jack (period {} frames)",
+        client.as_client().buffer_size()
+    );
+    let _ = closed_rx.recv();
+    Err("ringbuf closed".into())
+}
@@ -0,0 +1,70 @@
+use libpulse_binding::{
+    def::BufferAttr,
+    sample::{Format, Spec},
+    stream::Direction,
+};
+use libpulse_simple_binding::Simple;
+
+use crate::{
+    format::{SampleFormat, StreamFormat},
+    RingProd,
+};
+
+/// Size of the chunks the server sends, the smaller the lower the latency
+const FRAGMENT_MS: u32 = 5;
+
+fn pulse_format(fmt: SampleFormat) -> Format {
+    match fmt {
+        SampleFormat::S16LE => Format::S16le,
+        SampleFormat::S24LE => Format::S24le,
+        SampleFormat::S32LE => Format::S32le,
+        SampleFormat::F32LE => Format::F32le,
+    }
+}
+
+pub fn main(
+    source_name: String,
+    format: StreamFormat,
+    mut prod: RingProd,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let spec = Spec {
+        format: pulse_format(format.sample_format),
+        channels: format.channels.try_into().unwrap_or(u8::MAX),
+        rate: format.rate,
+    };
+    if !spec.is_valid() {
+        return Err(format!("pulseaudio doesn't support {format}").into());
+    }
+    let frame_bytes = format.frame_bytes();
+    let fragment = (format.rate * FRAGMENT_MS / 1000) as usize * frame_bytes;
+    let attr = BufferAttr {
+        maxlength: u32::MAX,
+        tlength: u32::MAX,
+        prebuf: u32::MAX,
+        minreq: u32::MAX,
+        fragsize: fragment as u32,
+    };
+    // "default" lets the server pick, like alsa's default device
+    let source = Some(source_name.as_str()).filter(|x| *x != "default");
+    let pulse = Simple::new(
+        None,
+        "ihatelatency",
+        Direction::Record,
+        source,
+        "ihatelatency",
+        &spec,
+        None,
+        Some(&attr),
+    )
+    .map_err(|err| format!("pulseaudio: {err}"))?;
+    log::info!("capturing {format} from {source_name} (fragment {fragment} bytes)");
+    let mut buf = vec![0u8; fragment];
+    loop {
+        pulse
+            .read(&mut buf)
+            .map_err(|err| format!("pulseaudio: {err}"))?;
+        if !crate::push_all(&mut prod, &buf) {
+            return Err("ringbuf closed".into());
+        }
+    }
+}
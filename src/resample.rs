@@ -0,0 +1,188 @@
+//! Fine-grained resampling for clock drift compensation
+//!
+//! The sender's and receiver's sound cards never run at exactly the same rate, so the player
+//! consumes audio slightly faster or slower than it arrives. Resampling it by a few ppm makes up
+//! for that without audible skips.
+
+use crate::format::StreamFormat;
+
+/// Frames needed around the interpolated position (one before, two after)
+const TAPS: usize = 4;
+
+pub struct Resampler {
+    format: StreamFormat,
+    channels: usize,
+    /// Decoded input frames, interleaved
+    input: Vec<f32>,
+    /// Position of the next output frame in `input`, in frames (always at least 1)
+    pos: f64,
+    bytes: Vec<u8>,
+}
+
+impl Resampler {
+    pub fn new(format: StreamFormat) -> Self {
+        let channels = usize::from(format.channels);
+        Self {
+            format,
+            channels,
+            // start with a silent frame to interpolate from
+            input: vec![0.0; channels],
+            pos: 1.0,
+            bytes: Vec::new(),
+        }
+    }
+    fn input_frames(&self) -> usize {
+        self.input.len() / self.channels
+    }
+    /// Fill `out` with audio taken from `input`, consuming `ratio` input frames per output frame
+    ///
+    /// `input` fills the given buffer with as many whole frames as it can, returning the amount
+    /// of bytes filled. Returns the amount of bytes written, which is less than `out.len()` if
+    /// `input` ran out of audio (but always whole frames).
+    pub fn process(
+        &mut self,
+        out: &mut [u8],
+        ratio: f64,
+        mut input: impl FnMut(&mut [u8]) -> usize,
+    ) -> usize {
+        let fmt = self.format.sample_format;
+        let frame_bytes = self.format.frame_bytes();
+        let frames = out.len() / frame_bytes;
+        if frames == 0 {
+            return 0;
+        }
+        // last input frame the interpolation will touch
+        let last = (self.pos + (frames - 1) as f64 * ratio) as usize + TAPS - 2;
+        let wanted = (last + 1).saturating_sub(self.input_frames());
+        self.bytes.resize(wanted * frame_bytes, 0);
+        let len = input(&mut self.bytes);
+        self.input.extend(
+            self.bytes[..len]
+                .chunks_exact(fmt.bytes())
+                .map(|x| fmt.decode(x)),
+        );
+        let input_frames = self.input_frames();
+        let mut written = 0;
+        for frame in out.chunks_exact_mut(frame_bytes) {
+            let i = self.pos as usize;
+            if i + TAPS - 2 >= input_frames {
+                break;
+            }
+            let t = (self.pos - i as f64) as f32;
+            let ch = self.channels;
+            for (c, sample) in frame.chunks_exact_mut(fmt.bytes()).enumerate() {
+                let x = |n: usize| self.input[n * ch + c];
+                fmt.encode(cubic(x(i - 1), x(i), x(i + 1), x(i + 2), t), sample);
+            }
+            self.pos += ratio;
+            written += frame_bytes;
+        }
+        // only keep the frames still needed for interpolation
+        let drop = (self.pos as usize - 1).min(self.input_frames());
+        self.input.drain(..drop * self.channels);
+        self.pos -= drop as f64;
+        written
+    }
+}
+
+/// Catmull-Rom interpolation between `x1` and `x2`
+fn cubic(x0: f32, x1: f32, x2: f32, x3: f32, t: f32) -> f32 {
+    let a = -0.5 * x0 + 1.5 * x1 - 1.5 * x2 + 0.5 * x3;
+    let b = x0 - 2.5 * x1 + 2.0 * x2 - 0.5 * x3;
+    let c = -0.5 * x0 + 0.5 * x2;
+    ((a * t + b) * t + c) * t + x1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::SampleFormat;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::F32LE,
+    };
+    /// Frames per callback (10ms)
+    const PERIOD: usize = 480;
+
+    /// Resample a ramp rising by `step` per frame (after the initial silent frame) for one
+    /// second, returning the left channel and the amount of input frames consumed
+    fn resample_ramp(ratio: f64, step: f32) -> (Vec<f32>, usize) {
+        let mut resampler = Resampler::new(FORMAT);
+        let frame_bytes = FORMAT.frame_bytes();
+        let mut consumed = 0;
+        let mut out = vec![0; PERIOD * frame_bytes];
+        let mut left = Vec::new();
+        for _ in 0..FORMAT.rate as usize / PERIOD {
+            let written = resampler.process(&mut out, ratio, |buf| {
+                for frame in buf.chunks_exact_mut(frame_bytes) {
+                    consumed += 1;
+                    let x = consumed as f32 * step;
+                    frame[..4].copy_from_slice(&x.to_le_bytes());
+                    frame[4..].copy_from_slice(&(-x).to_le_bytes());
+                }
+                buf.len()
+            });
+            assert_eq!(written, out.len());
+            left.extend(out.chunks_exact(frame_bytes).map(|x| {
+                assert_eq!(
+                    x[..4],
+                    (-f32::from_le_bytes(x[4..].try_into().unwrap())).to_le_bytes()
+                );
+                f32::from_le_bytes(x[..4].try_into().unwrap())
+            }));
+        }
+        (left, consumed)
+    }
+
+    #[test]
+    fn passes_audio_through_at_unit_ratio() {
+        let (left, consumed) = resample_ramp(1.0, 1.0 / 65536.0);
+        for (i, x) in left.iter().enumerate() {
+            assert_eq!(*x, (i + 1) as f32 / 65536.0);
+        }
+        // plus the frames to interpolate towards
+        assert_eq!(consumed, left.len() + TAPS - 2);
+    }
+
+    #[test]
+    fn follows_the_ratio_at_the_drift_limits() {
+        let step = 1.0 / 65536.0;
+        for ppm in [-500.0, -10.0, 10.0, 500.0] {
+            let ratio = 1.0 + ppm / 1e6;
+            let (left, consumed) = resample_ramp(ratio, step);
+            // interpolating a ramp has to land exactly on it
+            for (i, x) in left.iter().enumerate() {
+                let expected = (1.0 + i as f64 * ratio) as f32 * step;
+                assert!(
+                    (x - expected).abs() < 1e-5,
+                    "{ppm}ppm: {x} instead of {expected}"
+                );
+            }
+            let expected = left.len() as f64 * ratio;
+            assert!(
+                (consumed as f64 - expected).abs() <= TAPS as f64,
+                "{ppm}ppm: consumed {consumed} frames instead of {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn writes_whole_frames_when_input_runs_out() {
+        let mut resampler = Resampler::new(FORMAT);
+        let frame_bytes = FORMAT.frame_bytes();
+        let mut out = vec![0; PERIOD * frame_bytes];
+        let mut available = 100 * frame_bytes;
+        let written = resampler.process(&mut out, 1.0 + 500e-6, |buf| {
+            let len = buf.len().min(available);
+            available -= len;
+            len
+        });
+        assert_eq!(written % frame_bytes, 0);
+        assert!(written > 0 && written < out.len());
+        // and picks up where it left off once there's more
+        let written = resampler.process(&mut out, 1.0, |buf| buf.len());
+        assert_eq!(written, out.len());
+    }
+}
@@ -0,0 +1,341 @@
+//! RTP framing, for talking to standard tools (ffmpeg, GStreamer, VLC, PipeWire's RTP modules)
+//!
+//! PCM is sent as big-endian L16 or L24 and Opus as specified by RFC 7587. Instead of our own
+//! format header, the stream format is described out of band by an SDP file, so both sides have
+//! to be told the format (and codec) beforehand. The RTP sequence numbers and timestamps are
+//! turned into our own packet numbering, so received RTP streams get the same loss concealment
+//! and reordering as ours.
+
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    net::SocketAddr,
+    ops::Range,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    codec::Codec,
+    format::{SampleFormat, StreamFormat},
+    packet,
+};
+
+pub const HEADER_LEN: usize = 12;
+// RTP packets get built in place of ours
+const _: () = assert!(HEADER_LEN == packet::HEADER_LEN);
+const VERSION: u8 = 2;
+/// First dynamic payload type, which is what other tools default to as well
+const DYNAMIC_PT: u8 = 96;
+/// Opus timestamps always count at 48kHz, whatever the actual rate
+#[cfg(feature = "opus")]
+const OPUS_CLOCK: u32 = 48000;
+
+/// Whether RTP can carry audio in this format with this codec
+pub fn check(format: StreamFormat, codec: Codec) -> Result<(), String> {
+    match (codec, format.sample_format) {
+        (Codec::Pcm, SampleFormat::S16LE | SampleFormat::S24LE) => Ok(()),
+        (Codec::Pcm, fmt) => Err(format!("RTP can't carry {fmt}, only s16le and s24le")),
+        #[cfg(feature = "opus")]
+        (Codec::Opus, _) => Ok(()),
+    }
+}
+
+fn payload_type(format: StreamFormat, codec: Codec) -> u8 {
+    // the static payload types of RFC 3551
+    match (codec, format.sample_format, format.rate, format.channels) {
+        (Codec::Pcm, SampleFormat::S16LE, 44100, 2) => 10,
+        (Codec::Pcm, SampleFormat::S16LE, 44100, 1) => 11,
+        _ => DYNAMIC_PT,
+    }
+}
+
+/// How many timestamp units a frame takes
+fn clock_scale(format: StreamFormat, codec: Codec) -> u32 {
+    #[cfg(not(feature = "opus"))]
+    let _ = format;
+    match codec {
+        Codec::Pcm => 1,
+        #[cfg(feature = "opus")]
+        Codec::Opus => OPUS_CLOCK / format.rate,
+    }
+}
+
+/// Convert PCM samples between our little-endian formats and RTP's big-endian ones
+pub fn swap_bytes(data: &mut [u8], format: StreamFormat) {
+    for sample in data.chunks_exact_mut(format.sample_format.bytes()) {
+        sample.reverse();
+    }
+}
+
+/// Random number, without pulling in a dependency for it
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+/// Session description for a stream sent to (or received at) `addr`
+pub fn sdp(format: StreamFormat, codec: Codec, addr: SocketAddr) -> String {
+    let pt = payload_type(format, codec);
+    let ip = addr.ip();
+    let family = if ip.is_ipv4() { "IP4" } else { "IP6" };
+    let session = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs());
+    let mut ret = format!(
+        "v=0\r\n\
+         o=- {session} 0 IN {family} {ip}\r\n\
+         s=ihatelatency\r\n\
+         c=IN {family} {ip}\r\n\
+         t=0 0\r\n\
+         m=audio {} RTP/AVP {pt}\r\n",
+        addr.port(),
+    );
+    match codec {
+        Codec::Pcm => {
+            let encoding = match format.sample_format {
+                SampleFormat::S24LE => "L24",
+                _ => "L16",
+            };
+            ret += &format!(
+                "a=rtpmap:{pt} {encoding}/{}/{}\r\n",
+                format.rate, format.channels
+            );
+        }
+        #[cfg(feature = "opus")]
+        Codec::Opus => {
+            // opus is always declared as 48kHz stereo, the fmtp says what's actually sent
+            let stereo = u8::from(format.channels == 2);
+            ret += &format!("a=rtpmap:{pt} opus/{OPUS_CLOCK}/2\r\n");
+            ret += &format!("a=fmtp:{pt} stereo={stereo}; sprop-stereo={stereo}\r\n");
+        }
+    }
+    ret
+}
+
+/// Sender side RTP state
+#[derive(Debug)]
+pub struct Sender {
+    pt: u8,
+    ssrc: u32,
+    scale: u32,
+    /// Random offsets of the sequence numbers and timestamps, as recommended by RFC 3550
+    seq_base: u16,
+    ts_base: u32,
+    /// Whether the first packet was sent yet
+    started: bool,
+}
+
+impl Sender {
+    pub fn new(format: StreamFormat, codec: Codec) -> Self {
+        let (a, b) = (random(), random());
+        Self {
+            pt: payload_type(format, codec),
+            ssrc: a as u32,
+            scale: clock_scale(format, codec),
+            seq_base: (a >> 32) as u16,
+            ts_base: b as u32,
+            started: false,
+        }
+    }
+    /// Write the RTP header of the packet that `header` would have numbered
+    pub fn write(&mut self, header: packet::Header, buf: &mut [u8]) {
+        // the marker bit flags the start of a talkspurt, which for us is the start of the stream
+        let marker = if self.started { 0 } else { 0x80 };
+        self.started = true;
+        let seq = self.seq_base.wrapping_add(header.seq as u16);
+        let ts = self
+            .ts_base
+            .wrapping_add(header.pos.wrapping_mul(self.scale));
+        buf[0] = VERSION << 6;
+        buf[1] = marker | self.pt;
+        buf[2..4].copy_from_slice(&seq.to_be_bytes());
+        buf[4..8].copy_from_slice(&ts.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.ssrc.to_be_bytes());
+    }
+}
+
+/// A received RTP packet
+pub struct Packet {
+    /// Our own numbering of the packet
+    pub header: packet::Header,
+    /// Where the payload is in the datagram
+    pub payload: Range<usize>,
+    /// Whether this is the first packet from a new sender (or a restarted one)
+    pub new_source: bool,
+}
+
+/// Receiver side RTP state
+#[derive(Debug)]
+pub struct Receiver {
+    scale: u32,
+    /// SSRC, RTP sequence number and timestamp of the last packet, with our numbering of it
+    last: Option<(u32, u16, u32, packet::Header)>,
+}
+
+impl Receiver {
+    pub fn new(format: StreamFormat, codec: Codec) -> Self {
+        Self {
+            scale: clock_scale(format, codec),
+            last: None,
+        }
+    }
+    pub fn parse(&mut self, data: &[u8]) -> Option<Packet> {
+        let payload = payload(data)?;
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let ts = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+        // extend the wrapping RTP counters relative to the last packet, which is close by
+        let (header, new_source) = match self.last {
+            Some((last_ssrc, last_seq, last_ts, last)) if last_ssrc == ssrc => {
+                let seq_delta = seq.wrapping_sub(last_seq) as i16;
+                let ts_delta = ts.wrapping_sub(last_ts) as i32 / self.scale as i32;
+                let header = packet::Header {
+                    seq: last.seq.wrapping_add_signed(seq_delta.into()),
+                    pos: last.pos.wrapping_add_signed(ts_delta),
+                };
+                (header, false)
+            }
+            _ => {
+                log::info!("rtp stream {ssrc:08x} started");
+                (packet::Header { seq: 0, pos: 0 }, true)
+            }
+        };
+        self.last = Some((ssrc, seq, ts, header));
+        Some(Packet {
+            header,
+            payload,
+            new_source,
+        })
+    }
+}
+
+/// Whether a datagram is a well formed RTP packet
+pub fn is_packet(data: &[u8]) -> bool {
+    payload(data).is_some()
+}
+
+/// Where the payload of an RTP packet is, skipping CSRCs, header extensions and padding
+fn payload(data: &[u8]) -> Option<Range<usize>> {
+    if data.len() < HEADER_LEN || data[0] >> 6 != VERSION {
+        return None;
+    }
+    let csrcs = usize::from(data[0] & 0x0f);
+    let mut start = HEADER_LEN + 4 * csrcs;
+    if data[0] & 0x10 != 0 {
+        // header extension: 2 bytes of profile specific data, then its length in words
+        let ext = data.get(start..start + 4)?;
+        start += 4 + 4 * usize::from(u16::from_be_bytes([ext[2], ext[3]]));
+    }
+    let mut end = data.len();
+    if data[0] & 0x20 != 0 {
+        // padding, with its length in the last byte
+        end = end.checked_sub(usize::from(*data.last()?))?;
+    }
+    (start <= end).then_some(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::S16LE,
+    };
+
+    /// An RTP packet with the given first byte (CSRC count and flags), followed by `rest`
+    fn packet(first: u8, seq: u16, ts: u32, rest: &[u8]) -> Vec<u8> {
+        let mut ret = vec![VERSION << 6 | first, DYNAMIC_PT];
+        ret.extend(seq.to_be_bytes());
+        ret.extend(ts.to_be_bytes());
+        ret.extend(0x1234_5678u32.to_be_bytes());
+        ret.extend(rest);
+        ret
+    }
+
+    #[test]
+    fn numbers_packets_like_the_sender() {
+        let mut sender = Sender::new(FORMAT, Codec::Pcm);
+        // about to wrap around
+        (sender.seq_base, sender.ts_base) = (u16::MAX - 1, u32::MAX - 100);
+        let mut receiver = Receiver::new(FORMAT, Codec::Pcm);
+        let mut buf = [0; HEADER_LEN + 4];
+        for seq in 0..5 {
+            let header = packet::Header {
+                seq,
+                pos: seq * 240,
+            };
+            sender.write(header, &mut buf);
+            assert_eq!(buf[1] & 0x80 != 0, seq == 0, "marker bit");
+            let packet = receiver.parse(&buf).unwrap();
+            assert_eq!(packet.header, header);
+            assert_eq!(packet.payload, HEADER_LEN..buf.len());
+            assert_eq!(packet.new_source, seq == 0);
+        }
+    }
+
+    #[test]
+    fn follows_reordered_packets() {
+        let mut receiver = Receiver::new(FORMAT, Codec::Pcm);
+        receiver.parse(&packet(0, 10, 1000, &[])).unwrap();
+        let header = receiver.parse(&packet(0, 12, 1480, &[])).unwrap().header;
+        assert_eq!(header, packet::Header { seq: 2, pos: 480 });
+        let header = receiver.parse(&packet(0, 11, 1240, &[])).unwrap().header;
+        assert_eq!(header, packet::Header { seq: 1, pos: 240 });
+    }
+
+    #[test]
+    fn restarts_numbering_for_new_sources() {
+        let mut receiver = Receiver::new(FORMAT, Codec::Pcm);
+        receiver.parse(&packet(0, 10, 1000, &[])).unwrap();
+        let mut other = packet(0, 500, 50000, &[]);
+        other[8..12].copy_from_slice(&[1, 2, 3, 4]);
+        let packet = receiver.parse(&other).unwrap();
+        assert!(packet.new_source);
+        assert_eq!(packet.header, packet::Header { seq: 0, pos: 0 });
+    }
+
+    #[test]
+    fn skips_csrcs_extensions_and_padding() {
+        let audio = [1, 2, 3, 4];
+        let csrcs = [0xaa; 8];
+        // profile specific bits, then the length of one word
+        let ext = [0xbe, 0xde, 0, 1, 0xcc, 0xcc, 0xcc, 0xcc];
+        let padding = [0, 0, 3];
+        for (first, rest) in [
+            (0x02, [&csrcs[..], &audio].concat()),
+            (0x10, [&ext[..], &audio].concat()),
+            (0x20, [&audio[..], &padding].concat()),
+            (0x32, [&csrcs[..], &ext, &audio, &padding].concat()),
+        ] {
+            let data = packet(first, 1, 1, &rest);
+            let payload = Receiver::new(FORMAT, Codec::Pcm)
+                .parse(&data)
+                .unwrap()
+                .payload;
+            assert_eq!(data[payload], audio, "first byte {first:02x}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let audio = [1, 2, 3, 4];
+        let mut wrong_version = packet(0, 1, 1, &audio);
+        wrong_version[0] = 1 << 6;
+        for data in [
+            packet(0, 1, 1, &[])[..HEADER_LEN - 1].to_vec(),
+            wrong_version,
+            // more CSRCs than fit
+            packet(0x02, 1, 1, &audio),
+            // truncated extension header, then an extension longer than the packet
+            packet(0x10, 1, 1, &[0xbe, 0xde]),
+            packet(0x10, 1, 1, &[0xbe, 0xde, 0, 2, 0, 0, 0, 0]),
+            // more padding than payload, or than the whole packet
+            packet(0x20, 1, 1, &[0, 0, 0, 9]),
+            packet(0x20, 1, 1, &[])[..HEADER_LEN].to_vec(),
+        ] {
+            assert!(!is_packet(&data), "{data:02x?}");
+            assert!(Receiver::new(FORMAT, Codec::Pcm).parse(&data).is_none());
+        }
+        assert!(is_packet(&packet(0, 1, 1, &audio)));
+    }
+}
@@ -0,0 +1,330 @@
+//! Inaudible buffer size corrections
+//!
+//! Instead of cutting audio out of the stream (or repeating some of it), which clicks, the
+//! stream is crossfaded with a shifted copy of itself. The shift is picked close to the requested
+//! amount so that the two copies line up as well as possible, which hides the seam even in the
+//! middle of a tone.
+
+use ringbuf::traits::{Consumer, Observer};
+
+use crate::{format::StreamFormat, RingCons};
+
+const XFADE_MS: usize = 5;
+/// How far the shift may deviate from the requested amount to find a good match
+const SEARCH_MS: usize = 5;
+/// Max amount of audio that can be inserted at once
+const MAX_INSERT_MS: usize = 20;
+/// Max amount of audio taken out of the ring buffer ahead of time
+const MAX_PENDING_MS: usize = MAX_INSERT_MS + 2 * (SEARCH_MS + XFADE_MS);
+
+pub struct Splicer {
+    format: StreamFormat,
+    frame_bytes: usize,
+    xfade: usize,
+    search: usize,
+    max_insert: usize,
+    max_pending: usize,
+    /// Recently played frames, to repeat when inserting audio
+    history: Vec<u8>,
+    /// Audio to play before continuing with the ring buffer
+    pending: Vec<u8>,
+    pending_pos: usize,
+    /// Room to splice in, so that splicing doesn't allocate in the audio callback
+    scratch: Vec<u8>,
+}
+
+impl Splicer {
+    pub fn new(format: StreamFormat) -> Self {
+        let rate = format.rate as usize;
+        let frame_bytes = format.frame_bytes();
+        let (max_insert, search) = (rate * MAX_INSERT_MS / 1000, rate * SEARCH_MS / 1000);
+        let max_pending = rate * MAX_PENDING_MS / 1000;
+        Self {
+            format,
+            frame_bytes,
+            xfade: rate * XFADE_MS / 1000,
+            search,
+            max_insert,
+            max_pending,
+            history: Vec::with_capacity((max_insert + search) * frame_bytes),
+            pending: Vec::with_capacity(max_pending * frame_bytes),
+            pending_pos: 0,
+            scratch: Vec::with_capacity((max_insert + search + max_pending) * frame_bytes),
+        }
+    }
+    /// Amount of buffered frames that were already taken out of the ring buffer
+    pub fn pending_frames(&self) -> usize {
+        (self.pending.len() - self.pending_pos) / self.frame_bytes
+    }
+    /// Take up to `out.len()` bytes of audio (whole frames only), returning the amount taken
+    pub fn pop(&mut self, cons: &mut RingCons, out: &mut [u8]) -> usize {
+        let out_len = out.len() / self.frame_bytes * self.frame_bytes;
+        let pending = &self.pending[self.pending_pos..];
+        let mut len = pending.len().min(out_len);
+        out[..len].copy_from_slice(&pending[..len]);
+        self.pending_pos += len;
+        let rest = (out_len - len).min(cons.occupied_len() / self.frame_bytes * self.frame_bytes);
+        len += cons.pop_slice(&mut out[len..len + rest]);
+        let keep = (self.max_insert + self.search) * self.frame_bytes;
+        let played = &out[len.saturating_sub(keep)..len];
+        let excess = (self.history.len() + played.len()).saturating_sub(keep);
+        self.history.drain(..excess);
+        self.history.extend_from_slice(played);
+        len
+    }
+    /// Make sure at least `frames` frames (but no more than fit) are pending, if the ring buffer
+    /// has enough
+    fn fill(&mut self, cons: &mut RingCons, frames: usize) -> usize {
+        self.pending.drain(..self.pending_pos);
+        self.pending_pos = 0;
+        let frames = frames.min(self.max_pending);
+        let missing = (frames * self.frame_bytes).saturating_sub(self.pending.len());
+        let missing = missing.min(cons.occupied_len() / self.frame_bytes * self.frame_bytes);
+        let len = self.pending.len();
+        self.pending.resize(len + missing, 0);
+        let popped = cons.pop_slice(&mut self.pending[len..]);
+        self.pending.truncate(len + popped);
+        self.pending.len() / self.frame_bytes
+    }
+    /// Remove about `frames` frames from the upcoming audio, returning the amount removed
+    pub fn remove(&mut self, cons: &mut RingCons, frames: usize) -> usize {
+        let pending = self.fill(cons, self.xfade);
+        // audio before the shifts to search never gets played, so it can go right away
+        let skip = frames.saturating_sub(self.search).saturating_sub(pending);
+        let skip = skip.min(cons.occupied_len() / self.frame_bytes);
+        cons.skip(skip * self.frame_bytes);
+        let frames = frames - skip;
+        let avail = self.fill(cons, frames + self.search + self.xfade);
+        let max = avail.saturating_sub(self.xfade);
+        if max == 0 {
+            return skip;
+        }
+        let shift = self.best_shift(&self.pending, &self.pending, self.range(frames, max), |x| x);
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.pending);
+        self.splice(shift);
+        skip + shift
+    }
+    /// Insert about `frames` frames into the upcoming audio by repeating some of the most recently
+    /// played audio, returning the amount inserted
+    pub fn insert(&mut self, cons: &mut RingCons, frames: usize) -> usize {
+        let frames = frames.min(self.max_insert);
+        let history = self.history.len() / self.frame_bytes;
+        let pending = self.fill(cons, self.xfade);
+        // whatever gets inserted has to fit in with what's pending
+        let max = history.min(self.max_pending.saturating_sub(pending));
+        if pending < self.xfade || max == 0 {
+            return 0;
+        }
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&self.history);
+        self.scratch.extend_from_slice(&self.pending);
+        let shift = self.best_shift(&self.pending, &self.scratch, self.range(frames, max), |x| {
+            history - x
+        });
+        self.splice(history - shift);
+        shift
+    }
+    /// Shifts to search around `frames`, up to `max`
+    fn range(&self, frames: usize, max: usize) -> std::ops::RangeInclusive<usize> {
+        let end = (frames + self.search).min(max);
+        frames.saturating_sub(self.search).clamp(1, end)..=end
+    }
+    /// Replace the pending audio with the audio in `scratch` starting at frame `start`, with the
+    /// start of the pending audio crossfaded into it
+    fn splice(&mut self, start: usize) {
+        let fmt = self.format.sample_format;
+        let to = &mut self.scratch[start * self.frame_bytes..];
+        for (i, (out, from)) in to
+            .chunks_exact_mut(self.frame_bytes)
+            .zip(self.pending.chunks_exact(self.frame_bytes))
+            .take(self.xfade)
+            .enumerate()
+        {
+            let t = (i + 1) as f32 / (self.xfade + 1) as f32;
+            for (out, from) in out
+                .chunks_exact_mut(fmt.bytes())
+                .zip(from.chunks_exact(fmt.bytes()))
+            {
+                let x = fmt.decode(out) * t + fmt.decode(from) * (1.0 - t);
+                fmt.encode(x, out);
+            }
+        }
+        self.pending.clear();
+        self.pending.extend_from_slice(to);
+    }
+    /// Find the shift in `range` for which `to` (starting at the frame given by `start`) best
+    /// matches the start of `from`
+    fn best_shift(
+        &self,
+        from: &[u8],
+        to: &[u8],
+        range: std::ops::RangeInclusive<usize>,
+        start: impl Fn(usize) -> usize,
+    ) -> usize {
+        let fmt = self.format.sample_format;
+        let mono = |buf: &[u8], frame: usize| -> f32 {
+            buf[frame * self.frame_bytes..(frame + 1) * self.frame_bytes]
+                .chunks_exact(fmt.bytes())
+                .map(|x| fmt.decode(x))
+                .sum()
+        };
+        let len = self.xfade.min(from.len() / self.frame_bytes);
+        let (mut best, mut best_corr) = (*range.start(), f32::MIN);
+        for shift in range {
+            let start = start(shift);
+            if (start + len) * self.frame_bytes > to.len() {
+                break;
+            }
+            let (mut xy, mut xx, mut yy) = (0.0, 0.0, 0.0);
+            for i in 0..len {
+                let (x, y) = (mono(from, i), mono(to, start + i));
+                xy += x * y;
+                xx += x * x;
+                yy += y * y;
+            }
+            let corr = xy / (xx * yy).sqrt().max(f32::EPSILON);
+            if corr > best_corr {
+                (best, best_corr) = (shift, corr);
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ringbuf::traits::{Producer, Split};
+    use ringbuf_blocking::BlockingRb;
+
+    use super::*;
+    use crate::{format::SampleFormat, RingProd};
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 48000,
+        channels: 2,
+        sample_format: SampleFormat::F32LE,
+    };
+    const FREQ: f32 = 440.0;
+    /// Max difference between consecutive samples of the tone
+    const MAX_STEP: f32 = 2.0 * std::f32::consts::PI * FREQ / 48000.0;
+
+    /// A ring buffer holding `secs` seconds of a tone
+    fn tone(secs: usize) -> (RingProd, RingCons) {
+        let frames = FORMAT.rate as usize * secs;
+        let (mut prod, cons) = BlockingRb::new(frames * FORMAT.frame_bytes()).split();
+        for i in 0..frames {
+            let x = (i as f32 * MAX_STEP).sin();
+            for _ in 0..FORMAT.channels {
+                prod.push_slice(&x.to_le_bytes());
+            }
+        }
+        (prod, cons)
+    }
+
+    /// Play everything left (with `splicer` already having played `played`), checking that the
+    /// audio continues smoothly and that the splicer didn't need to allocate
+    fn assert_continuous(splicer: &mut Splicer, cons: &mut RingCons, mut played: Vec<f32>) {
+        let capacities = |x: &Splicer| {
+            (
+                x.history.capacity(),
+                x.pending.capacity(),
+                x.scratch.capacity(),
+            )
+        };
+        let mut out = vec![0; 480 * FORMAT.frame_bytes()];
+        loop {
+            let len = splicer.pop(cons, &mut out);
+            if len == 0 {
+                break;
+            }
+            played.extend(out[..len].chunks_exact(FORMAT.frame_bytes()).map(|x| {
+                assert_eq!(x[..4], x[4..]);
+                f32::from_le_bytes(x[..4].try_into().unwrap())
+            }));
+        }
+        assert_eq!(capacities(splicer), capacities(&Splicer::new(FORMAT)));
+        for (i, x) in played.windows(2).enumerate() {
+            assert!(
+                (x[1] - x[0]).abs() < 1.5 * MAX_STEP,
+                "jumped from {} to {} at frame {i}",
+                x[0],
+                x[1]
+            );
+        }
+    }
+
+    fn play(splicer: &mut Splicer, cons: &mut RingCons, frames: usize) -> Vec<f32> {
+        let mut out = vec![0; frames * FORMAT.frame_bytes()];
+        assert_eq!(splicer.pop(cons, &mut out), out.len());
+        out.chunks_exact(FORMAT.frame_bytes())
+            .map(|x| f32::from_le_bytes(x[..4].try_into().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn removes_about_as_much_as_requested() {
+        let search = FORMAT.rate as usize * SEARCH_MS / 1000;
+        // up to far more than it ever holds on to
+        for frames in [10, 480, 4800, 24000] {
+            let (_prod, mut cons) = tone(1);
+            let mut splicer = Splicer::new(FORMAT);
+            let played = play(&mut splicer, &mut cons, 480);
+            let removed = splicer.remove(&mut cons, frames);
+            assert!(
+                removed.abs_diff(frames) <= search,
+                "removed {removed} of {frames}"
+            );
+            let left = cons.occupied_len() / FORMAT.frame_bytes() + splicer.pending_frames();
+            assert_eq!(left + removed + 480, FORMAT.rate as usize);
+            assert_continuous(&mut splicer, &mut cons, played);
+        }
+    }
+
+    #[test]
+    fn inserts_about_as_much_as_requested() {
+        let max_insert = FORMAT.rate as usize * MAX_INSERT_MS / 1000;
+        let search = FORMAT.rate as usize * SEARCH_MS / 1000;
+        for frames in [10, 480, 4800] {
+            let (_prod, mut cons) = tone(1);
+            let mut splicer = Splicer::new(FORMAT);
+            let played = play(&mut splicer, &mut cons, 4800);
+            let inserted = splicer.insert(&mut cons, frames);
+            let frames = frames.min(max_insert);
+            assert!(
+                inserted.abs_diff(frames) <= search,
+                "inserted {inserted} of {frames}"
+            );
+            let left = cons.occupied_len() / FORMAT.frame_bytes() + splicer.pending_frames();
+            assert_eq!(left, FORMAT.rate as usize - 4800 + inserted);
+            assert_continuous(&mut splicer, &mut cons, played);
+        }
+    }
+
+    #[test]
+    fn inserts_nothing_without_history() {
+        let (_prod, mut cons) = tone(1);
+        let mut splicer = Splicer::new(FORMAT);
+        assert_eq!(splicer.insert(&mut cons, 480), 0);
+    }
+
+    #[test]
+    fn crossfades_over_the_whole_fade() {
+        let xfade = FORMAT.rate as usize * XFADE_MS / 1000;
+        let mut splicer = Splicer::new(FORMAT);
+        let level = |x: f32, frames| x.to_le_bytes().repeat(frames * 2);
+        splicer.pending = level(1.0, 2 * xfade);
+        splicer.scratch = level(-1.0, 2 * xfade);
+        splicer.splice(0);
+        let faded = splicer
+            .pending
+            .chunks_exact(FORMAT.frame_bytes())
+            .map(|x| f32::from_le_bytes(x[..4].try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(faded.len(), 2 * xfade);
+        // falling all the way, but never reaching the new level before the end of the fade
+        assert!(faded[..xfade].windows(2).all(|x| x[1] < x[0]));
+        assert!(faded[0] < 1.0 && faded[xfade - 1] > -1.0);
+        assert!(faded[xfade..].iter().all(|x| *x == -1.0));
+    }
+}
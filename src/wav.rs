@@ -0,0 +1,238 @@
+//! Just enough of the WAV format to read and write plain PCM files
+
+use std::io::{self, Read};
+
+use crate::format::{SampleFormat, StreamFormat};
+
+pub const MAGIC: [u8; 4] = *b"RIFF";
+pub const HEADER_LEN: usize = 44;
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// Longer format chunks than this (the extensible one is 40 bytes) are rejected as corrupt
+const MAX_FMT_LEN: usize = 64;
+
+fn format_tag(fmt: SampleFormat) -> u16 {
+    match fmt {
+        SampleFormat::S16LE | SampleFormat::S24LE | SampleFormat::S32LE => FORMAT_PCM,
+        SampleFormat::F32LE => FORMAT_FLOAT,
+    }
+}
+
+/// Header of a file with `data_len` bytes of audio
+pub fn header(format: StreamFormat, data_len: u32) -> [u8; HEADER_LEN] {
+    let frame_bytes = format.frame_bytes() as u32;
+    let mut ret = [0u8; HEADER_LEN];
+    ret[..4].copy_from_slice(&MAGIC);
+    ret[4..8].copy_from_slice(&data_len.saturating_add(36).to_le_bytes());
+    ret[8..16].copy_from_slice(b"WAVEfmt ");
+    ret[16..20].copy_from_slice(&16u32.to_le_bytes());
+    ret[20..22].copy_from_slice(&format_tag(format.sample_format).to_le_bytes());
+    ret[22..24].copy_from_slice(&format.channels.to_le_bytes());
+    ret[24..28].copy_from_slice(&format.rate.to_le_bytes());
+    ret[28..32].copy_from_slice(&(format.rate * frame_bytes).to_le_bytes());
+    ret[32..34].copy_from_slice(&(frame_bytes as u16).to_le_bytes());
+    ret[34..36].copy_from_slice(&(format.sample_format.bytes() as u16 * 8).to_le_bytes());
+    ret[36..40].copy_from_slice(b"data");
+    ret[40..44].copy_from_slice(&data_len.to_le_bytes());
+    ret
+}
+
+/// Read the rest of a header (after `MAGIC`) up to the start of the audio, checking that it
+/// matches `format`
+pub fn read_header(
+    reader: &mut impl Read,
+    format: StreamFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut riff = [0u8; 8];
+    reader.read_exact(&mut riff)?;
+    if riff[4..] != *b"WAVE" {
+        return Err("not a WAV file".into());
+    }
+    let mut fmt_ok = false;
+    loop {
+        let mut chunk = [0u8; 8];
+        reader.read_exact(&mut chunk)?;
+        let len = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        match &chunk[..4] {
+            b"fmt " => {
+                let len = len as usize;
+                if len < 16 {
+                    return Err("WAV format chunk too short".into());
+                }
+                if len > MAX_FMT_LEN {
+                    return Err(format!("WAV format chunk too long ({len} bytes)").into());
+                }
+                let mut buf = [0u8; MAX_FMT_LEN];
+                // including the padding to an even length
+                reader.read_exact(&mut buf[..len + len % 2])?;
+                let fmt = &buf[..len];
+                let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+                let mut tag = u16_at(0);
+                if tag == FORMAT_EXTENSIBLE && fmt.len() >= 26 {
+                    // the subformat GUID starts with the actual format tag
+                    tag = u16_at(24);
+                }
+                let rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+                let bits = usize::from(u16_at(14));
+                if tag != format_tag(format.sample_format)
+                    || u16_at(2) != format.channels
+                    || rate != format.rate
+                    || bits != format.sample_format.bytes() * 8
+                {
+                    return Err(format!(
+                        "WAV file has format {tag} {bits}bit {rate}Hz {}ch, not {format}",
+                        u16_at(2)
+                    )
+                    .into());
+                }
+                fmt_ok = true;
+            }
+            b"data" if fmt_ok => return Ok(()),
+            b"data" => return Err("WAV file has no format chunk".into()),
+            _ => {
+                // chunks are padded to an even length
+                let len = u64::from(len) + u64::from(len % 2);
+                io::copy(&mut reader.by_ref().take(len), &mut io::sink())?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMAT: StreamFormat = StreamFormat {
+        rate: 44100,
+        channels: 2,
+        sample_format: SampleFormat::S16LE,
+    };
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut ret = id.to_vec();
+        ret.extend((data.len() as u32).to_le_bytes());
+        ret.extend(data);
+        if data.len() % 2 == 1 {
+            ret.push(0);
+        }
+        ret
+    }
+
+    /// A file (after `MAGIC`) made of `chunks`
+    fn file(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut ret = b"\0\0\0\0WAVE".to_vec();
+        ret.extend(chunks.concat());
+        ret
+    }
+
+    /// The format chunk `header` writes
+    fn fmt() -> Vec<u8> {
+        header(FORMAT, 0)[20..36].to_vec()
+    }
+
+    fn read(file: &[u8], format: StreamFormat) -> Result<(), String> {
+        read_header(&mut &file[..], format).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn reads_its_own_header() {
+        let header = header(FORMAT, 1000);
+        let mut reader = &header[MAGIC.len()..];
+        read_header(&mut reader, FORMAT).unwrap();
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn skips_unknown_chunks() {
+        let file = file(&[
+            chunk(b"LIST", b"odd"),
+            chunk(b"fmt ", &fmt()),
+            chunk(b"fact", &[0; 4]),
+            chunk(b"data", &[1, 2, 3, 4]),
+        ]);
+        let mut reader = &file[..];
+        read_header(&mut reader, FORMAT).unwrap();
+        assert_eq!(reader, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reads_extensible_formats() {
+        let mut ext = fmt();
+        ext[..2].copy_from_slice(&FORMAT_EXTENSIBLE.to_le_bytes());
+        // extension size, valid bits and channel mask, then the subformat GUID
+        ext.extend([22, 0, 16, 0, 3, 0, 0, 0]);
+        ext.extend(FORMAT_PCM.to_le_bytes());
+        ext.extend([0; 14]);
+        read(&file(&[chunk(b"fmt ", &ext), chunk(b"data", &[])]), FORMAT).unwrap();
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        for (file, err) in [
+            (b"\0\0\0\0RIFX".to_vec(), "not a WAV file"),
+            (file(&[chunk(b"data", &[])]), "no format chunk"),
+            (file(&[chunk(b"fmt ", &fmt()[..14])]), "too short"),
+            (file(&[chunk(b"fmt ", &[0; 66])]), "too long"),
+            // truncated in the middle of a chunk
+            (
+                file(&[chunk(b"fmt ", &fmt())])[..20].to_vec(),
+                "failed to fill whole buffer",
+            ),
+            (
+                file(&[chunk(b"LIST", &[0; 100])])[..50].to_vec(),
+                "failed to fill whole buffer",
+            ),
+        ] {
+            let result = read(&file, FORMAT);
+            assert!(
+                result.as_ref().is_err_and(|x| x.contains(err)),
+                "{result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_huge_format_chunks_without_reading_them() {
+        let mut file = file(&[]);
+        file.extend(b"fmt ");
+        file.extend(u32::MAX.to_le_bytes());
+        let result = read(&file, FORMAT);
+        assert!(
+            result.as_ref().is_err_and(|x| x.contains("too long")),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        for format in [
+            StreamFormat {
+                rate: 48000,
+                ..FORMAT
+            },
+            StreamFormat {
+                channels: 1,
+                ..FORMAT
+            },
+            StreamFormat {
+                sample_format: SampleFormat::S24LE,
+                ..FORMAT
+            },
+            StreamFormat {
+                sample_format: SampleFormat::F32LE,
+                ..FORMAT
+            },
+        ] {
+            let file = file(&[chunk(b"fmt ", &fmt()), chunk(b"data", &[])]);
+            let result = read(&file, format);
+            assert!(
+                result
+                    .as_ref()
+                    .is_err_and(|x| x.contains("WAV file has format")),
+                "{result:?}"
+            );
+        }
+    }
+}
@@ -0,0 +1,902 @@
+//! End-to-end tests running the binary over 127.0.0.1, with stdin/stdout standing in for the
+//! sound card on either side (or the test itself standing in for the other side)
+
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// s16le stereo, the default format
+const FRAME_BYTES: usize = 4;
+const RATE: usize = 48000;
+/// Stream header: magic, version, sample format, channels, rate, codec
+const HEADER: [u8; 13] = *b"ihl\0\x02\x01\x02\0\x80\xbb\0\0\0";
+/// UDP audio packet magic, followed by the sequence number and the position of its first frame
+const PACKET_MAGIC: &[u8] = b"ihl\x01";
+const PACKET_HEADER_LEN: usize = 12;
+const SUBSCRIBE: &[u8] = b"ihl\x02";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Child process that gets killed when the test ends, whichever way it ends
+struct Proc(Child);
+
+impl Drop for Proc {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl Proc {
+    fn spawn(args: &[&str]) -> Self {
+        Self::spawn_with(args, Stdio::null())
+    }
+    fn spawn_with(args: &[&str], stdout: Stdio) -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_ihatelatency"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(stdout)
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self(child)
+    }
+    fn stdin(&mut self) -> ChildStdin {
+        self.0.stdin.take().unwrap()
+    }
+    /// Wait for the process to exit on its own
+    fn wait(&mut self) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            if let Some(status) = self.0.try_wait().unwrap() {
+                return status.success();
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        panic!("process didn't exit");
+    }
+}
+
+/// An address nothing is listening on yet
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Deterministic noise, so that any shifted or corrupted audio is noticed
+fn pattern(ms: usize) -> Vec<u8> {
+    let mut x = 0x12345678u32;
+    (0..RATE * ms / 1000 * FRAME_BYTES)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        })
+        .collect()
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let start = Instant::now();
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(conn) => {
+                conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+                return conn;
+            }
+            Err(_) if start.elapsed() < TIMEOUT => std::thread::sleep(Duration::from_millis(50)),
+            Err(err) => panic!("connect: {err}"),
+        }
+    }
+}
+
+fn accept(listener: &TcpListener) -> TcpStream {
+    listener.set_nonblocking(true).unwrap();
+    let start = Instant::now();
+    loop {
+        match listener.accept() {
+            Ok((conn, _)) => {
+                conn.set_nonblocking(false).unwrap();
+                conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+                return conn;
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock && start.elapsed() < TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(10))
+            }
+            Err(err) => panic!("accept: {err}"),
+        }
+    }
+}
+
+fn read_header(conn: &mut TcpStream) {
+    let mut header = [0u8; HEADER.len()];
+    conn.read_exact(&mut header).unwrap();
+    assert_eq!(header, HEADER);
+}
+
+/// Feed `data` to a recorder's stdin from another thread, closing it afterwards
+fn feed(mut stdin: ChildStdin, data: Vec<u8>) {
+    std::thread::spawn(move || {
+        let _ = stdin.write_all(&data);
+    });
+}
+
+/// Receive a recorder's UDP stream until `len` bytes of audio arrived or it stops, returning
+/// the audio put in place by position and how many bytes of it arrived
+fn receive_udp(sock: &UdpSocket, len: usize) -> (Vec<u8>, usize) {
+    sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut audio = vec![0u8; len];
+    let mut received = 0;
+    let mut buf = [0u8; 2048];
+    while received < len {
+        let Ok(n) = sock.recv(&mut buf) else {
+            break;
+        };
+        let packet = &buf[..n];
+        if packet == HEADER {
+            continue;
+        }
+        assert_eq!(&packet[..4], PACKET_MAGIC);
+        let pos = u32::from_le_bytes(packet[8..12].try_into().unwrap()) as usize * FRAME_BYTES;
+        let payload = &packet[PACKET_HEADER_LEN..];
+        audio[pos..pos + payload.len()].copy_from_slice(payload);
+        received += payload.len();
+    }
+    (audio, received)
+}
+
+/// Write a pre-shared key file unique to the calling test
+fn key_file(name: &str, secret: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ihatelatency-{}-{name}", std::process::id()));
+    std::fs::write(&path, secret).unwrap();
+    path
+}
+
+/// Length of the longest stretch of `needle`, starting at its beginning, found in `haystack`
+fn longest_prefix_match(haystack: &[u8], needle: &[u8]) -> usize {
+    let Some(start) = haystack
+        .windows(FRAME_BYTES * 64)
+        .position(|x| x == &needle[..FRAME_BYTES * 64])
+    else {
+        return 0;
+    };
+    haystack[start..]
+        .iter()
+        .zip(needle)
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+/// Player writing to stdout, with a fixed buffer, no resampling and no concealment, so that the
+/// audio comes out untouched
+struct Player {
+    _proc: Proc,
+    stdout: ChildStdout,
+}
+
+impl Player {
+    /// Start a player with `args` before the `play` command
+    fn spawn(args: &[&str]) -> Self {
+        Self::spawn_with(args, &[])
+    }
+    /// Start a player with `args` before the `play` command and `play_args` after it
+    fn spawn_with(args: &[&str], play_args: &[&str]) -> Self {
+        let play = [
+            "play",
+            "--backend",
+            "stdout",
+            "-s",
+            "9600",
+            "--max-drift-ppm",
+            "0",
+            "--conceal",
+            "silence",
+        ];
+        let mut proc = Proc::spawn_with(&[args, &play, play_args].concat(), Stdio::piped());
+        let stdout = proc.0.stdout.take().unwrap();
+        Self {
+            _proc: proc,
+            stdout,
+        }
+    }
+    /// Everything played in the next `secs` seconds
+    fn play(&mut self, secs: usize) -> Vec<u8> {
+        let mut played = vec![0u8; secs * RATE * FRAME_BYTES];
+        self.stdout.read_exact(&mut played).unwrap();
+        played
+    }
+    /// Check that most of `audio` comes out intact within the next two seconds, returning
+    /// everything played in them
+    fn assert_plays(&mut self, audio: &[u8]) -> Vec<u8> {
+        let played = self.play(2);
+        assert_intact(&played, audio);
+        played
+    }
+}
+
+/// Check that most of `audio` got played intact (the very end gets stretched when the buffer
+/// runs dry)
+fn assert_intact(played: &[u8], audio: &[u8]) {
+    let matched = longest_prefix_match(played, audio);
+    assert!(
+        matched >= audio.len() * 9 / 10,
+        "only {matched} bytes came out intact"
+    );
+}
+
+/// Socket sending to `addr`
+fn udp_sender(addr: &str) -> UdpSocket {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.connect(addr).unwrap();
+    sock
+}
+
+/// Wait until a UDP player is bound to `addr`, probing it with empty datagrams (which it drops)
+/// until they stop bouncing
+fn wait_for_udp(addr: &str) {
+    let sock = udp_sender(addr);
+    sock.set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < TIMEOUT, "player never came up");
+        sock.send(&[]).unwrap();
+        match sock.recv(&mut [0u8; 16]) {
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => {}
+            _ => return,
+        }
+    }
+}
+
+/// Wait until `count` sockets are bound to UDP `port`, for multicast players (which don't bounce
+/// anything, so they can't be probed)
+fn wait_for_udp_sockets(port: u16, count: usize) {
+    let port = format!(":{port:04X} ");
+    let start = Instant::now();
+    loop {
+        let table = std::fs::read_to_string("/proc/net/udp").unwrap();
+        // the local address is the second column
+        let bound = table
+            .lines()
+            .filter_map(|x| x.split_whitespace().nth(1))
+            .filter(|x| format!("{x} ").ends_with(&port))
+            .count();
+        if bound >= count {
+            return;
+        }
+        assert!(start.elapsed() < TIMEOUT, "players never came up");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Send a stream the way a recorder would, announcing its format first (a player that isn't
+/// listening to us makes the sends bounce, so they may fail)
+fn send_stream(sock: &UdpSocket, audio: &[u8]) {
+    let _ = sock.send(&HEADER);
+    let frames = 240;
+    for (i, chunk) in audio.chunks(frames * FRAME_BYTES).enumerate() {
+        let mut packet = PACKET_MAGIC.to_vec();
+        packet.extend_from_slice(&(i as u32).to_le_bytes());
+        packet.extend_from_slice(&((i * frames) as u32).to_le_bytes());
+        packet.extend_from_slice(chunk);
+        let _ = sock.send(&packet);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+/// Stream audio from a recorder reading stdin to a player, each started with its own `args`
+/// before the command, and check that it comes out intact
+fn end_to_end(player: &[&str], recorder: &[&str]) {
+    let mut play = Player::spawn(player);
+    let addr = player[player.iter().position(|x| *x == "-a").unwrap() + 1];
+    // the recorder doesn't retry right away, so don't make it try before the player is up
+    if player.contains(&"-u") {
+        wait_for_udp(addr);
+    } else {
+        drop(connect(addr.parse().unwrap()));
+    }
+    let mut rec = Proc::spawn(&[recorder, &["record", "--backend", "stdin"]].concat());
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    play.assert_plays(&audio);
+}
+
+#[test]
+fn tcp_connecting_recorder_is_byte_exact() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut rec = Proc::spawn(&["-a", &addr, "record", "--backend", "stdin"]);
+    let audio = pattern(300);
+    feed(rec.stdin(), audio.clone());
+    let mut conn = accept(&listener);
+    read_header(&mut conn);
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).unwrap();
+    assert!(received == audio, "received audio differs");
+    assert!(rec.wait());
+}
+
+#[test]
+fn tcp_listening_recorder_is_byte_exact() {
+    let addr = free_addr();
+    let mut rec = Proc::spawn(&[
+        "-l",
+        "-a",
+        &addr.to_string(),
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let mut conn = connect(addr);
+    // audio recorded before a client subscribes doesn't reach it
+    read_header(&mut conn);
+    let audio = pattern(300);
+    feed(rec.stdin(), audio.clone());
+    let mut received = vec![0u8; audio.len()];
+    conn.read_exact(&mut received).unwrap();
+    assert!(received == audio, "received audio differs");
+}
+
+#[test]
+fn udp_connecting_recorder_loses_little() {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap().to_string();
+    let mut rec = Proc::spawn(&["-u", "-a", &addr, "record", "--backend", "stdin"]);
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    let (received, len) = receive_udp(&sock, audio.len());
+    assert!(len >= audio.len() * 99 / 100, "only got {len} bytes");
+    // whatever arrived must be in the right place
+    let wrong = received
+        .chunks(FRAME_BYTES)
+        .zip(audio.chunks(FRAME_BYTES))
+        .filter(|(a, b)| a != b && a.iter().any(|x| *x != 0))
+        .count();
+    assert_eq!(wrong, 0);
+}
+
+#[test]
+fn udp_listening_recorder_serves_subscribers() {
+    let addr = free_addr();
+    let mut rec = Proc::spawn(&[
+        "-u",
+        "-l",
+        "-a",
+        &addr.to_string(),
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    sock.connect(addr).unwrap();
+    sock.set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let start = Instant::now();
+    let mut buf = [0u8; 2048];
+    // keep subscribing until the recorder is up and acknowledges with the header
+    loop {
+        assert!(start.elapsed() < TIMEOUT, "never got the header");
+        sock.send(SUBSCRIBE).unwrap();
+        if matches!(sock.recv(&mut buf), Ok(n) if buf[..n] == HEADER) {
+            break;
+        }
+    }
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    let (received, len) = receive_udp(&sock, audio.len());
+    assert!(len >= audio.len() * 99 / 100, "only got {len} bytes");
+    assert!(longest_prefix_match(&received, &audio) >= audio.len() * 9 / 10);
+}
+
+#[test]
+fn tcp_listening_player_plays_the_stream() {
+    let addr = free_addr();
+    let mut play = Player::spawn(&["-l", "-a", &addr.to_string()]);
+    let audio = pattern(500);
+    let mut conn = connect(addr);
+    conn.write_all(&HEADER).unwrap();
+    conn.write_all(&audio).unwrap();
+    play.assert_plays(&audio);
+}
+
+#[test]
+fn tcp_connecting_player_plays_the_stream() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut play = Player::spawn(&["-a", &addr]);
+    let mut conn = accept(&listener);
+    let audio = pattern(500);
+    conn.write_all(&HEADER).unwrap();
+    conn.write_all(&audio).unwrap();
+    play.assert_plays(&audio);
+}
+
+#[test]
+fn tcp_player_reconnects_after_recorder_restarts() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut play = Player::spawn(&["-a", &addr.to_string()]);
+    let audio = pattern(500);
+    let other: Vec<u8> = audio.iter().map(|x| x ^ 0xa5).collect();
+    let (first, second) = (audio.clone(), other.clone());
+    let rec = std::thread::spawn(move || {
+        let mut conn = accept(&listener);
+        conn.write_all(&HEADER).unwrap();
+        conn.write_all(&first).unwrap();
+        // the recorder goes away, and comes back at the same address
+        drop((conn, listener));
+        std::thread::sleep(Duration::from_millis(500));
+        let listener = TcpListener::bind(addr).unwrap();
+        let mut conn = accept(&listener);
+        conn.write_all(&HEADER).unwrap();
+        conn.write_all(&second).unwrap();
+        conn
+    });
+    let played = play.play(6);
+    let _conn = rec.join().unwrap();
+    assert_intact(&played, &audio);
+    assert_intact(&played, &other);
+}
+
+#[test]
+fn udp_end_to_end() {
+    let addr = free_addr().to_string();
+    end_to_end(&["-u", "-l", "-a", &addr], &["-u", "-a", &addr]);
+}
+
+#[test]
+fn impaired_tcp_is_still_byte_exact() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut rec = Proc::spawn(&[
+        "--impair",
+        "delay=20ms,jitter=10ms,dist=normal,rate=4mbit",
+        "-a",
+        &addr,
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let audio = pattern(300);
+    feed(rec.stdin(), audio.clone());
+    let mut conn = accept(&listener);
+    read_header(&mut conn);
+    let mut received = Vec::new();
+    conn.read_to_end(&mut received).unwrap();
+    assert!(received == audio, "received audio differs");
+    assert!(rec.wait());
+}
+
+#[test]
+fn player_rejects_impair() {
+    let mut play = Proc::spawn(&[
+        "--impair",
+        "delay=20ms",
+        "-a",
+        "127.0.0.1:9",
+        "play",
+        "--backend",
+        "stdout",
+    ]);
+    assert!(!play.wait());
+}
+
+#[test]
+fn impaired_udp_loses_and_duplicates_packets() {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap().to_string();
+    let mut rec = Proc::spawn(&[
+        "--impair",
+        "jitter=5ms,loss=10%,dup=10%,reorder=10%",
+        "-u",
+        "-a",
+        &addr,
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let audio = pattern(1000);
+    feed(rec.stdin(), audio.clone());
+    // duplicates count towards the length, so wait for the stream to stop instead
+    let (received, len) = receive_udp(&sock, audio.len() * 2);
+    let intact = received
+        .chunks(FRAME_BYTES)
+        .zip(audio.chunks(FRAME_BYTES))
+        .filter(|(a, b)| a == b)
+        .count();
+    let frames = audio.len() / FRAME_BYTES;
+    assert!(
+        intact >= frames * 8 / 10 && intact <= frames * 97 / 100,
+        "{intact} of {frames} frames arrived"
+    );
+    assert!(len > intact * FRAME_BYTES, "nothing got duplicated");
+    let wrong = received
+        .chunks(FRAME_BYTES)
+        .zip(audio.chunks(FRAME_BYTES))
+        .filter(|(a, b)| a != b && a.iter().any(|x| *x != 0))
+        .count();
+    assert_eq!(wrong, 0);
+}
+
+#[test]
+fn udp_player_switches_senders_after_inactivity() {
+    let addr = free_addr().to_string();
+    let mut play = Player::spawn(&["-i", "1", "-u", "-l", "-a", &addr]);
+    wait_for_udp(&addr);
+    let audio = pattern(500);
+    let other: Vec<u8> = audio.iter().map(|x| x ^ 0xa5).collect();
+    let (first, second) = (audio.clone(), other.clone());
+    let senders = std::thread::spawn(move || {
+        send_stream(&udp_sender(&addr), &first);
+        // ignored, the player sticks to the first sender while it's active
+        send_stream(&udp_sender(&addr), &[0x11; 100 * RATE / 1000 * FRAME_BYTES]);
+        // the first sender goes quiet for longer than the inactivity timeout
+        std::thread::sleep(Duration::from_secs(2));
+        send_stream(&udp_sender(&addr), &second);
+    });
+    let played = play.play(5);
+    senders.join().unwrap();
+    assert_intact(&played, &audio);
+    assert_intact(&played, &other);
+    assert!(!played.chunks(FRAME_BYTES).any(|x| x == [0x11; FRAME_BYTES]));
+}
+
+#[test]
+fn psk_tcp_end_to_end() {
+    let key = key_file("psk_tcp", "correct horse battery staple\n");
+    let key = key.to_str().unwrap();
+    let addr = free_addr().to_string();
+    end_to_end(
+        &["--psk-file", key, "-l", "-a", &addr],
+        &["--psk-file", key, "-a", &addr],
+    );
+}
+
+#[test]
+fn psk_udp_end_to_end() {
+    let key = key_file("psk_udp_e2e", "correct horse battery staple");
+    let key = key.to_str().unwrap();
+    let addr = free_addr().to_string();
+    end_to_end(
+        &["--psk-file", key, "-u", "-l", "-a", &addr],
+        &["--psk-file", key, "-u", "-a", &addr],
+    );
+}
+
+#[test]
+fn psk_recorder_rejects_peers_without_the_key() {
+    let key = key_file("psk_reject", "correct horse battery staple");
+    let addr = free_addr();
+    let mut rec = Proc::spawn(&[
+        "--psk-file",
+        key.to_str().unwrap(),
+        "-l",
+        "-a",
+        &addr.to_string(),
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    feed(rec.stdin(), pattern(2000));
+    let mut conn = connect(addr);
+    // a handshake and a confirmation record that can't be valid
+    conn.write_all(&[0x55; 32]).unwrap();
+    conn.write_all(&[20, 0]).unwrap();
+    conn.write_all(&[0xaa; 20]).unwrap();
+    let mut received = Vec::new();
+    let _ = conn.read_to_end(&mut received);
+    // all we got is the recorder's side of the handshake
+    assert!(received.len() < 100, "got {} bytes", received.len());
+    assert!(!received.windows(4).any(|x| x == &HEADER[..4]));
+}
+
+#[test]
+fn psk_udp_player_ignores_unsealed_packets() {
+    let key = key_file("psk_udp", "correct horse battery staple");
+    let addr = free_addr();
+    let mut play = Proc::spawn_with(
+        &[
+            "--psk-file",
+            key.to_str().unwrap(),
+            "-u",
+            "-l",
+            "-a",
+            &addr.to_string(),
+            "play",
+            "--backend",
+            "stdout",
+        ],
+        Stdio::piped(),
+    );
+    let mut stdout = play.0.stdout.take().unwrap();
+    wait_for_udp(&addr.to_string());
+    let sock = udp_sender(&addr.to_string());
+    // what a recorder without the key would send
+    send_stream(&sock, &pattern(500));
+    let mut played = vec![0u8; RATE * FRAME_BYTES];
+    stdout.read_exact(&mut played).unwrap();
+    assert!(played.iter().all(|x| *x == 0), "injected audio got played");
+}
+
+#[test]
+fn tcp_player_rejects_denied_peers() {
+    let addr = free_addr();
+    let _play = Proc::spawn(&[
+        "-i",
+        "60",
+        "--deny",
+        "127.0.0.0/8",
+        "-l",
+        "-a",
+        &addr.to_string(),
+        "play",
+        "--backend",
+        "stdout",
+    ]);
+    let mut conn = connect(addr);
+    let _ = conn.write_all(&HEADER);
+    // hung up on, long before the inactivity timeout (reads time out well before it too)
+    let mut buf = [0u8; 16];
+    let res = conn.read(&mut buf);
+    assert!(matches!(res, Ok(0)) || res.is_err_and(|x| x.kind() != ErrorKind::WouldBlock));
+}
+
+#[test]
+fn udp_player_only_plays_allowed_senders() {
+    let addr = free_addr().to_string();
+    let mut play = Player::spawn(&["--allow", "127.0.0.1/32", "-u", "-l", "-a", &addr]);
+    wait_for_udp(&addr);
+    // a stray sender that gets in first
+    let stray = UdpSocket::bind("127.0.0.2:0").unwrap();
+    stray.connect(&addr).unwrap();
+    stray.send(&HEADER).unwrap();
+    for i in 0..50u32 {
+        let mut packet = PACKET_MAGIC.to_vec();
+        packet.extend_from_slice(&i.to_le_bytes());
+        packet.extend_from_slice(&(i * 240).to_le_bytes());
+        packet.extend_from_slice(&[0x11; 240 * FRAME_BYTES]);
+        stray.send(&packet).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let mut rec = Proc::spawn(&["-u", "-a", &addr, "record", "--backend", "stdin"]);
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    let played = play.assert_plays(&audio);
+    assert!(!played.chunks(FRAME_BYTES).any(|x| x == [0x11; FRAME_BYTES]));
+}
+
+#[test]
+fn udp_player_mixes_senders() {
+    let addr = free_addr().to_string();
+    let mut play = Player::spawn_with(
+        &["-u", "-l", "-a", &addr],
+        &["--mix", "--gain", "127.0.0.2=0.5"],
+    );
+    wait_for_udp(&addr);
+    // steady levels, which add up to a steady level
+    let level = |x: i16| x.to_le_bytes().repeat(RATE * FRAME_BYTES / 2);
+    std::thread::scope(|s| {
+        for (ip, audio) in [("127.0.0.1:0", level(1000)), ("127.0.0.2:0", level(2000))] {
+            let sock = UdpSocket::bind(ip).unwrap();
+            sock.connect(&addr).unwrap();
+            s.spawn(move || send_stream(&sock, &audio));
+        }
+        let played = play.play(2);
+        let samples: Vec<i16> = played
+            .chunks(2)
+            .map(|x| i16::from_le_bytes([x[0], x[1]]))
+            .collect();
+        // 1000 + 2000 * 0.5, give or take float rounding; without the gain it would be 3000
+        let mixed = samples.iter().filter(|x| (**x - 2000).abs() <= 1).count();
+        assert!(mixed >= samples.len() / 4, "only {mixed} samples mixed");
+        assert!(samples.iter().all(|x| *x <= 2001));
+    });
+}
+
+#[test]
+fn rtp_recorder_sends_standard_rtp() {
+    let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    let sdp = std::env::temp_dir().join(format!("ihatelatency-{}.sdp", std::process::id()));
+    let mut rec = Proc::spawn(&[
+        "-u",
+        "--rtp",
+        "--sdp",
+        sdp.to_str().unwrap(),
+        "-a",
+        &addr.to_string(),
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    sock.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    let mut received = vec![0u8; audio.len()];
+    let mut first: Option<(u16, u32, u32)> = None;
+    let mut buf = [0u8; 2048];
+    let mut len = 0;
+    let mut packets = 0u16;
+    while len < audio.len() {
+        let n = sock.recv(&mut buf).unwrap();
+        let packet = &buf[..n];
+        // version 2, no padding, extension or CSRCs, dynamic payload type 96
+        assert_eq!(packet[0], 0x80);
+        assert_eq!(packet[1] & 0x7f, 96);
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let ts = u32::from_be_bytes(packet[4..8].try_into().unwrap());
+        let ssrc = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let (seq0, ts0, ssrc0) = *first.get_or_insert((seq, ts, ssrc));
+        assert_eq!(packet[1] & 0x80 != 0, seq == seq0, "marker bit");
+        assert_eq!(ssrc, ssrc0);
+        let pos = ts.wrapping_sub(ts0) as usize * FRAME_BYTES;
+        let payload = &packet[12..];
+        assert_eq!(seq, seq0.wrapping_add(packets));
+        packets += 1;
+        // L16 is big-endian
+        for (out, sample) in received[pos..pos + payload.len()]
+            .chunks_mut(2)
+            .zip(payload.chunks(2))
+        {
+            out.copy_from_slice(&[sample[1], sample[0]]);
+        }
+        len += payload.len();
+    }
+    assert!(received == audio, "received audio differs");
+    let sdp = std::fs::read_to_string(&sdp).unwrap();
+    assert!(sdp.contains("c=IN IP4 127.0.0.1\r\n"), "{sdp}");
+    assert!(
+        sdp.contains(&format!("m=audio {} RTP/AVP 96\r\n", addr.port())),
+        "{sdp}"
+    );
+    assert!(sdp.contains("a=rtpmap:96 L16/48000/2\r\n"), "{sdp}");
+}
+
+#[test]
+fn rtp_player_plays_standard_rtp() {
+    let addr = free_addr().to_string();
+    let mut play = Player::spawn(&["-u", "--rtp", "-l", "-a", &addr]);
+    // probed from another address, which the player mustn't stick to
+    wait_for_udp(&addr);
+    let sock = udp_sender(&addr);
+    let audio = pattern(500);
+    let frames = 240;
+    // start right before both counters wrap around
+    let (mut seq, mut ts) = (u16::MAX - 10, u32::MAX - 5 * frames as u32);
+    for chunk in audio.chunks(frames * FRAME_BYTES) {
+        let mut packet = vec![0x80, 96];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&ts.to_be_bytes());
+        packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+        for sample in chunk.chunks(2) {
+            packet.extend_from_slice(&[sample[1], sample[0]]);
+        }
+        sock.send(&packet).unwrap();
+        seq = seq.wrapping_add(1);
+        ts = ts.wrapping_add(frames as u32);
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    play.assert_plays(&audio);
+}
+
+#[test]
+fn recorder_reconnects_after_peer_death() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let mut rec = Proc::spawn(&["-a", &addr, "record", "--backend", "stdin"]);
+    // keep audio flowing, so the recorder notices the dead connection
+    feed(rec.stdin(), vec![0u8; 10 * RATE * FRAME_BYTES]);
+    let mut conn = accept(&listener);
+    read_header(&mut conn);
+    drop(conn);
+    let mut conn = accept(&listener);
+    read_header(&mut conn);
+}
+
+#[test]
+fn player_drops_inactive_connections() {
+    let addr = free_addr();
+    let _play = Proc::spawn(&[
+        "-i",
+        "1",
+        "-l",
+        "-a",
+        &addr.to_string(),
+        "play",
+        "--backend",
+        "stdout",
+    ]);
+    let mut conn = connect(addr);
+    conn.write_all(&HEADER).unwrap();
+    let start = Instant::now();
+    // the player hangs up once nothing arrived for a second
+    let mut buf = [0u8; 16];
+    let res = conn.read(&mut buf);
+    assert!(matches!(res, Ok(0)) || res.is_err_and(|x| x.kind() != ErrorKind::WouldBlock));
+    // no upper bound besides the read timeout, a loaded machine may take its time
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed >= Duration::from_millis(900),
+        "hung up after {elapsed:?}"
+    );
+}
+
+#[test]
+fn multicast_feeds_every_player() {
+    let port = free_addr().port();
+    let group = format!("239.255.73.1:{port}");
+    let net = ["-u", "-a", &group, "--multicast-interface", "127.0.0.1"];
+    let mut players: Vec<_> = (0..2)
+        .map(|_| Player::spawn(&[&["-l"], &net[..]].concat()))
+        .collect();
+    wait_for_udp_sockets(port, players.len());
+    let mut rec = Proc::spawn(&[&net[..], &["record", "--backend", "stdin"]].concat());
+    let audio = pattern(500);
+    feed(rec.stdin(), audio.clone());
+    for play in &mut players {
+        play.assert_plays(&audio);
+    }
+}
+
+#[test]
+fn discover_lists_listening_instances() {
+    let name = format!("discover-{}", std::process::id());
+    let addr = free_addr().to_string();
+    let _rec = Proc::spawn(&[
+        "--mdns-interface",
+        "127.0.0.1",
+        "--name",
+        &name,
+        "-l",
+        "-a",
+        &addr,
+        "record",
+        "--backend",
+        "stdin",
+    ]);
+    let expected = format!("{name}: tcp record at {addr}, s16le 48000Hz 2ch, pcm");
+    let start = Instant::now();
+    // the recorder may not be advertising yet, so look again until it is
+    loop {
+        let out = Command::new(env!("CARGO_BIN_EXE_ihatelatency"))
+            .args(["--mdns-interface", "127.0.0.1", "discover"])
+            .output()
+            .unwrap();
+        assert!(out.status.success());
+        let out = String::from_utf8(out.stdout).unwrap();
+        if out.lines().any(|x| x == expected) {
+            break;
+        }
+        assert!(start.elapsed() < TIMEOUT, "got {out:?}");
+    }
+}
+
+#[test]
+fn mdns_address_finds_the_player() {
+    let name = format!("player-{}", std::process::id());
+    let addr = free_addr().to_string();
+    end_to_end(
+        &[
+            "--mdns-interface",
+            "127.0.0.1",
+            "--name",
+            &name,
+            "-u",
+            "-l",
+            "-a",
+            &addr,
+        ],
+        &[
+            "--mdns-interface",
+            "127.0.0.1",
+            "-u",
+            "-a",
+            &format!("mdns:{name}"),
+        ],
+    );
+}